@@ -1,58 +1,340 @@
 use axum::{
-    body::Body,
-    http::{header, StatusCode},
-    response::IntoResponse,
-    Json,
+    body::{to_bytes, Body},
+    extract::{rejection::JsonRejection, Request},
+    http::{header, HeaderName, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+use tower_http::request_id::RequestId;
 
 pub type ApiResult<T> = Result<T, ApiError>;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Media type of the error responses, see RFC 7807.
+pub(crate) const PROBLEM_JSON: &str = "application/problem+json";
+
+/// The header name for the request ID used for tracing.
+pub(crate) static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Stable, machine-readable code of the error.
+/// The clients should match on the code, the messages may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    BadRequest,
+    /// Some of the request fields are invalid, see the `errors` of the response.
+    ValidationFailed,
+    /// The request body is well-formed JSON, but it doesn't match the expected schema.
+    InvalidBody,
+    /// There is no route for the requested path.
+    NotFound,
+    /// The request lacks valid credentials, see the `WWW-Authenticate` header.
+    Unauthorized,
+    /// The client is not allowed to make the request.
+    Forbidden,
+    UserNotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    /// The record was modified since the client read it, see the `If-Match` header.
+    PreconditionFailed,
+    /// The `Idempotency-Key` was already used for a different request.
+    IdempotencyKeyReused,
+    /// The request with the same `Idempotency-Key` is still being processed.
+    IdempotencyKeyInUse,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    InternalError,
+    /// The storage of the server can't make the backups.
+    BackupsNotSupported,
+    /// Another backup is being written, retry once it's complete.
+    BackupInProgress,
+    /// The server has too many requests in flight, retry after the `Retry-After` delay.
+    Overloaded,
+}
+
+impl ErrorCode {
+    /// The generic code of the status, used for the errors which don't come from
+    /// the handlers, e.g. the extractor rejections.
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::REQUEST_TIMEOUT => ErrorCode::RequestTimeout,
+            StatusCode::PRECONDITION_FAILED => ErrorCode::PreconditionFailed,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::InvalidBody,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Overloaded,
+            status if status.is_server_error() => ErrorCode::InternalError,
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+/// Code of the violation of a single request field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ViolationCode {
+    /// The field is missing or empty.
+    Required,
+    /// The field doesn't match the expected format.
+    InvalidFormat,
+    /// The field is well-formed, but its value isn't accepted.
+    InvalidValue,
+    /// The value is outside of the allowed range.
+    OutOfRange,
+    /// The value contains characters which are not allowed.
+    InvalidCharacters,
+    /// The value is too short or too long.
+    InvalidLength,
+    /// The date is not in the past.
+    NotInPast,
+}
+
+/// Violation of a single request field.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct FieldViolation {
+    /// Name of the field as sent by the client, e.g. `dateOfBirth` or `limit`.
+    pub field: String,
+    pub code: ViolationCode,
+    pub message: String,
+}
+
+impl FieldViolation {
+    pub(crate) fn new(field: &str, code: ViolationCode, message: &str) -> Self {
+        FieldViolation {
+            field: field.to_owned(),
+            code,
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// Error response in the `application/problem+json` format of RFC 7807.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub(crate) struct ApiError {
+    /// The errors don't have the documentation pages, so the type is always `about:blank`.
+    #[serde(rename = "type", skip_deserializing)]
+    pub problem_type: &'static str,
+    /// Reason phrase of the status code.
+    #[serde(skip_deserializing)]
+    pub title: &'static str,
     pub status: u16,
+    #[serde(rename = "detail")]
     pub message: String,
+    pub code: ErrorCode,
+    /// ID of the request which failed, set by the `problem_instance` middleware.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldViolation>,
 }
 
 impl ApiError {
-    pub(crate) fn new(status: impl Into<u16>, message: &str) -> Self {
+    pub(crate) fn new(status: impl Into<u16>, code: ErrorCode, message: &str) -> Self {
+        let status = status.into();
+        let title = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or_default();
+
         ApiError {
-            status: status.into(),
+            problem_type: "about:blank",
+            title,
+            status,
             message: message.to_owned(),
+            code,
+            instance: None,
+            errors: vec![],
         }
     }
 
-    pub(crate) fn bad_request(message: &str) -> Self {
-        ApiError::new(StatusCode::BAD_REQUEST, message)
+    /// Create the error with the generic code of the status.
+    pub(crate) fn from_status(status: StatusCode, message: &str) -> Self {
+        ApiError::new(status, ErrorCode::from_status(status), message)
+    }
+
+    /// Create the error listing all the invalid fields of the request.
+    /// The message of the violation is used as the message of the error if there is
+    /// only one, otherwise the messages are joined.
+    pub(crate) fn validation(errors: Vec<FieldViolation>) -> Self {
+        let message = errors
+            .iter()
+            .map(|violation| violation.message.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        ApiError {
+            errors,
+            ..ApiError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::ValidationFailed,
+                &message,
+            )
+        }
+    }
+
+    /// Create the error for a single invalid field.
+    pub(crate) fn invalid_field(field: &str, code: ViolationCode, message: &str) -> Self {
+        ApiError::validation(vec![FieldViolation::new(field, code, message)])
     }
 
     pub(crate) fn internal_server_error() -> ApiError {
-        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            "Internal server error",
+        )
+    }
+
+    pub(crate) fn user_not_found(username: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UserNotFound,
+            &format!("User '{}' was not found", username),
+        )
     }
 
-    pub(crate) fn not_found(message: &str) -> ApiError {
-        ApiError::new(StatusCode::NOT_FOUND, message)
+    pub(crate) fn precondition_failed(username: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PreconditionFailed,
+            &format!(
+                "The birthday of user '{}' was modified or removed. Get the current revision and try again",
+                username
+            ),
+        )
     }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
-        let status = self.status;
-        let res = Json(self).into_response();
-        let body: axum::body::Body = res.into_body();
+        let body = serde_json::to_vec(&self).expect("Failed to serialize the error");
 
-        axum::http::Response::builder()
-            .status(status)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body)
-            .expect("Failed to build response")
+        let mut response = axum::http::Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, PROBLEM_JSON)
+            .body(Body::from(body))
+            .expect("Failed to build response");
+        // Keep the error, so the middleware can fill in the request details.
+        response.extensions_mut().insert(self);
+
+        response
     }
 }
 
-impl From<axum::http::Response<Body>> for ApiError {
-    fn from(res: axum::http::Response<Body>) -> Self {
-        log::info!("res: {:?}", res);
-        let message: String = format!("{:?}", res.body());
-        ApiError::new(res.status().as_u16(), &message)
+/// Tower middleware that sets the `instance` of the error responses to the request ID,
+/// so the failed request can be found in the logs.
+/// It must run after the request ID is set.
+pub(crate) async fn problem_instance(request: Request, next: Next) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_owned);
+
+    let mut response = next.run(request).await;
+
+    let (Some(error), Some(request_id)) =
+        (response.extensions_mut().remove::<ApiError>(), request_id)
+    else {
+        return response;
+    };
+    let error = ApiError {
+        instance: Some(request_id),
+        ..error
+    };
+
+    // Only the body is replaced. The headers of the response are kept, e.g.
+    // `WWW-Authenticate` of the `401` or `Retry-After` of the `503`.
+    let (mut parts, _) = response.into_parts();
+    let (_, body) = error.into_response().into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    Response::from_parts(parts, body)
+}
+
+/// Maximal size of the plain text error body reused as the error message.
+const MAX_REJECTION_BODY: usize = 4096;
+
+/// Tower middleware that turns the error responses which are not problem documents
+/// into `ApiError`s. These are the responses of axum, e.g. the extractor rejections
+/// and the `405 Method Not Allowed`, as well as the responses of the tower layers,
+/// e.g. the `408 Request Timeout`.
+///
+/// The plain text body of the response is kept as the message of the error. The JSON
+/// bodies are passed through, as they are already structured, e.g. the readiness
+/// report of the `503` of `/readyz`.
+/// It should be the outermost layer, so it sees the responses of all the other layers.
+pub(crate) async fn problem_responses(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || is_json(&response) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let is_text = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain"));
+    let text = match to_bytes(body, MAX_REJECTION_BODY).await {
+        Ok(body) if is_text => String::from_utf8_lossy(&body).trim().to_owned(),
+        _ => String::new(),
+    };
+    let message = if text.is_empty() {
+        status.canonical_reason().unwrap_or_default()
+    } else {
+        &text
+    };
+
+    let error = ApiError {
+        instance: parts
+            .headers
+            .get(&X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned),
+        ..ApiError::from_status(status, message)
+    };
+
+    let mut response = error.into_response();
+    // Keep the headers of the original response, e.g. `Allow` of the `405`.
+    for (name, value) in parts.headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+
+    response
+}
+
+/// Check if the response is JSON, including the problem documents.
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .is_some_and(|media_type| media_type == "application/json" || media_type.ends_with("+json"))
+}
+
+/// Fallback handler of the routers, for the paths without a route.
+pub(crate) async fn route_not_found(uri: Uri) -> ApiError {
+    ApiError::from_status(
+        StatusCode::NOT_FOUND,
+        &format!("No route for '{}'", uri.path()),
+    )
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        log::debug!("Rejected the JSON body: {}", rejection);
+
+        ApiError::from_status(rejection.status(), &rejection.body_text())
     }
 }
 
@@ -60,9 +342,194 @@ impl From<anyhow::Error> for ApiError {
     fn from(error: anyhow::Error) -> Self {
         log::warn!("Encountered an unhandled error: {:?}", error);
 
-        Self {
-            status: 500,
-            message: "Ups... This should have never happened. Please contact the developers about this issue.".into(),
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            "Ups... This should have never happened. Please contact the developers about this issue.",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{middleware, routing::get, Json, Router};
+    use tower::ServiceExt;
+    use tower_http::{
+        request_id::{MakeRequestId, SetRequestIdLayer},
+        timeout::TimeoutLayer,
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct FixedRequestId;
+
+    impl MakeRequestId for FixedRequestId {
+        fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+            Some(RequestId::new("42".parse().unwrap()))
         }
     }
+
+    #[tokio::test]
+    async fn test_problem_response() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    ApiError::validation(vec![
+                        FieldViolation::new("limit", ViolationCode::OutOfRange, "Invalid limit."),
+                        FieldViolation::new(
+                            "cursor",
+                            ViolationCode::InvalidValue,
+                            "Invalid cursor.",
+                        ),
+                    ])
+                }),
+            )
+            .layer(middleware::from_fn(problem_instance))
+            .layer(SetRequestIdLayer::x_request_id(FixedRequestId));
+
+        let res = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Invalid limit. Invalid cursor.",
+                "code": "validation_failed",
+                "instance": "42",
+                "errors": [
+                    { "field": "limit", "code": "out_of_range", "message": "Invalid limit." },
+                    { "field": "cursor", "code": "invalid_value", "message": "Invalid cursor." },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_problem_response_keeps_headers() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        [(header::WWW_AUTHENTICATE, "Bearer")],
+                        ApiError::from_status(StatusCode::UNAUTHORIZED, "Missing credentials"),
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(problem_instance))
+            .layer(SetRequestIdLayer::x_request_id(FixedRequestId));
+
+        let res = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let error: ApiError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, ErrorCode::Unauthorized);
+        assert_eq!(error.instance.as_deref(), Some("42"));
+    }
+
+    async fn send(app: Router, request: Request) -> (StatusCode, ApiError, Response) {
+        let res = app.oneshot(request).await.unwrap();
+        let (parts, body) = res.into_parts();
+        assert_eq!(parts.headers[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        let error = serde_json::from_slice(&body).unwrap();
+
+        (
+            parts.status,
+            error,
+            Response::from_parts(parts, Body::empty()),
+        )
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|| async { "Hello" }))
+            .route(
+                "/json",
+                axum::routing::post(|Json(body): Json<u32>| async move { body.to_string() }),
+            )
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }),
+            )
+            .fallback(route_not_found)
+            .layer(TimeoutLayer::new(Duration::from_millis(10)))
+            .layer(middleware::from_fn(problem_responses))
+    }
+
+    #[tokio::test]
+    async fn test_route_not_found() {
+        let (status, error, _) =
+            send(app(), Request::get("/foo").body(Body::empty()).unwrap()).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error.code, ErrorCode::NotFound);
+        assert_eq!(error.message, "No route for '/foo'");
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let (status, error, res) =
+            send(app(), Request::delete("/").body(Body::empty()).unwrap()).await;
+
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error.code, ErrorCode::MethodNotAllowed);
+        assert_eq!(error.message, "Method Not Allowed");
+        assert!(res.headers().contains_key(header::ALLOW));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let (status, error, _) =
+            send(app(), Request::get("/slow").body(Body::empty()).unwrap()).await;
+
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(error.code, ErrorCode::RequestTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_json_rejections() {
+        let json = |content_type: Option<&str>, body: &'static str| {
+            let mut request = Request::post("/json");
+            if let Some(content_type) = content_type {
+                request = request.header(header::CONTENT_TYPE, content_type);
+            }
+            request.body(Body::from(body)).unwrap()
+        };
+
+        let (status, error, _) = send(app(), json(None, "42")).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error.code, ErrorCode::UnsupportedMediaType);
+
+        let (status, error, _) = send(app(), json(Some("application/json"), "nope")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.code, ErrorCode::BadRequest);
+        assert!(error
+            .message
+            .starts_with("Failed to parse the request body as JSON"));
+
+        let (status, error, _) = send(app(), json(Some("application/json"), "\"foo\"")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.code, ErrorCode::InvalidBody);
+    }
 }
@@ -0,0 +1,40 @@
+/// Operations recorded in the audit trail.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    /// The user's personal data was erased on request.
+    Erase,
+}
+
+impl AuditAction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Erase => "erase",
+        }
+    }
+}
+
+/// Entry of the audit trail, recorded along with the change it describes, see
+/// `BirthdayStore::delete_birthday`.
+/// It must not contain any personal data other than the username, so it can be
+/// retained after the user's data is erased. The time of the entry is set by the store.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub action: AuditAction,
+    pub username: String,
+    pub request_id: Option<String>,
+}
+
+impl AuditEntry {
+    /// Create an entry for the request currently being handled.
+    ///
+    /// The request ID is read from the MDC, set by `mdc_injector` each time the request
+    /// is polled.
+    pub(crate) fn for_current_request(action: AuditAction, username: &str) -> Self {
+        AuditEntry {
+            action,
+            username: username.to_owned(),
+            request_id: log_mdc::get("request_id", |id| id.map(str::to_owned)),
+        }
+    }
+}
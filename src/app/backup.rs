@@ -0,0 +1,421 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{async_trait, extract::State, http::StatusCode, Json};
+use futures::{stream::BoxStream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::app::{
+    api::{ApiError, ApiResult, ErrorCode},
+    clock::Clock,
+    migrations, Store,
+};
+
+/// Storage able to produce the snapshots of its data and to load them back.
+#[async_trait]
+pub(crate) trait BackupStore: Send + Sync {
+    /// Stream a consistent point-in-time snapshot of the database. The writes made
+    /// while the snapshot is streamed are not included.
+    async fn backup(&self) -> Result<BoxStream<'static, Result<Vec<u8>>>>;
+    /// Load the snapshot from the file into the empty database.
+    async fn restore(&self, file: &Path) -> Result<()>;
+}
+
+/// Error of the storage which can't make or load the backups, e.g. the remote
+/// SurrealDB connected over WebSocket.
+#[derive(Debug)]
+pub(crate) struct BackupsNotSupported(pub &'static str);
+
+impl fmt::Display for BackupsNotSupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for BackupsNotSupported {}
+
+/// Snapshot written by `write_backup`.
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BackupInfo {
+    pub file: PathBuf,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the file.
+    pub sha256: String,
+}
+
+/// Path of the file with the checksum of the backup, in the `sha256sum` format.
+pub(crate) fn checksum_path(file: &Path) -> PathBuf {
+    with_suffix(file, ".sha256")
+}
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(file.as_os_str());
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Write the snapshot of the database to the file, along with its checksum.
+///
+/// The snapshot is written to a temporary file first and renamed when it's complete,
+/// so a failed backup never leaves a truncated file behind.
+pub(crate) async fn write_backup(store: &Store, file: &Path) -> Result<BackupInfo> {
+    let mut snapshot = store.backup.backup().await?;
+    let partial = with_suffix(file, ".partial");
+    let mut output = File::create(&partial)
+        .await
+        .with_context(|| format!("Creating the backup file: {}", partial.display()))?;
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = snapshot.try_next().await? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        output.write_all(&chunk).await?;
+    }
+    output.sync_all().await?;
+    fs::rename(&partial, file).await?;
+
+    let sha256 = format!("{:x}", hasher.finalize());
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(checksum_path(file), format!("{}  {}\n", sha256, name)).await?;
+
+    Ok(BackupInfo {
+        file: file.to_owned(),
+        size,
+        sha256,
+    })
+}
+
+/// Check the file against the checksum written along with it.
+pub(crate) async fn verify_backup(file: &Path) -> Result<()> {
+    let checksum_file = checksum_path(file);
+    let checksum = fs::read_to_string(&checksum_file)
+        .await
+        .with_context(|| format!("Reading the checksum file: {}", checksum_file.display()))?;
+    let Some(expected) = checksum.split_whitespace().next() else {
+        bail!("The checksum file {} is empty", checksum_file.display());
+    };
+
+    let mut input = File::open(file)
+        .await
+        .with_context(|| format!("Opening the backup file: {}", file.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = input.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let actual = format!("{:x}", hasher.finalize());
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!(
+            "The checksum of {} doesn't match: expected {}, got {}",
+            file.display(),
+            expected,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+/// Verify the backup and load it into the empty database. The schema of the restored
+/// data is brought up to date, as the backup might have been made by an older version.
+pub(crate) async fn restore(store: &Store, file: &Path) -> Result<()> {
+    verify_backup(file).await?;
+
+    // The migrations are recorded in the database, so no version means no data.
+    if store.schema.schema_version().await? != 0 {
+        bail!("The backup can only be restored into an empty database");
+    }
+
+    store.backup.restore(file).await?;
+    migrations::migrate(store.schema.as_ref()).await?;
+
+    Ok(())
+}
+
+/// Directory where the backups made through the API are written.
+#[derive(Clone)]
+pub(crate) struct BackupDir {
+    dir: PathBuf,
+    /// Number of the newest backups kept, 0 keeps all of them.
+    keep: usize,
+    clock: Arc<dyn Clock>,
+    /// Held while the backup is written, so only one is written at a time.
+    writing: Arc<Mutex<()>>,
+}
+
+impl BackupDir {
+    pub(crate) fn new(dir: PathBuf, keep: usize, clock: impl Clock + 'static) -> Self {
+        BackupDir {
+            dir,
+            keep,
+            clock: Arc::new(clock),
+            writing: Arc::default(),
+        }
+    }
+
+    /// Path of the new backup, named after the current time, e.g.
+    /// `backup-20240615T120000.000Z.surql`.
+    fn next_file(&self) -> PathBuf {
+        let now = self.clock.now();
+        self.dir
+            .join(format!("backup-{}.surql", now.format("%Y%m%dT%H%M%S%.3fZ")))
+    }
+
+    /// Remove the oldest backups beyond the number kept, along with their checksums.
+    /// The files are named after the time they were made, so they sort by age.
+    async fn rotate(&self) -> Result<Vec<PathBuf>> {
+        if self.keep == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut files = vec![];
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with("backup-") && name.ends_with(".surql") {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+
+        let expired = files.len().saturating_sub(self.keep);
+        let expired: Vec<_> = files.drain(..expired).collect();
+        for file in &expired {
+            fs::remove_file(file).await?;
+            // The checksum might be missing if the backup failed after the rename.
+            let _ = fs::remove_file(checksum_path(file)).await;
+        }
+
+        Ok(expired)
+    }
+}
+
+/// API handler for making the backup of the database while the server is running.
+/// The backup is written to the backup directory on the server, and the oldest backups
+/// beyond the number kept are removed. The storages which can't make the backups are
+/// rejected with `501 Not Implemented`, and the requests made while another backup is
+/// written with `409 Conflict`.
+pub(crate) async fn create_backup(
+    State(store): State<Store>,
+    State(backups): State<BackupDir>,
+) -> ApiResult<(StatusCode, Json<BackupInfo>)> {
+    let Ok(_writing) = backups.writing.try_lock() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            ErrorCode::BackupInProgress,
+            "Another backup is being written",
+        ));
+    };
+    fs::create_dir_all(&backups.dir)
+        .await
+        .with_context(|| format!("Creating the backup directory: {}", backups.dir.display()))?;
+
+    let info = write_backup(&store, &backups.next_file())
+        .await
+        .map_err(|err| match err.downcast_ref::<BackupsNotSupported>() {
+            Some(err) => ApiError::new(
+                StatusCode::NOT_IMPLEMENTED,
+                ErrorCode::BackupsNotSupported,
+                &err.to_string(),
+            ),
+            None => err.into(),
+        })?;
+    log::info!(
+        "Written the backup {} of {} bytes",
+        info.file.display(),
+        info.size
+    );
+
+    // The new backup is complete, so failing to remove the old ones doesn't fail it.
+    match backups.rotate().await {
+        Ok(removed) => {
+            for file in removed {
+                log::info!("Removed the old backup {}", file.display());
+            }
+        }
+        Err(err) => log::warn!("Failed to remove the old backups: {:#}", err),
+    }
+
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::app::{
+        clock::FixedClock,
+        hello::store::{Birthday, InMemoryBirthdayStore, StoredBirthday, SurrealBirthdayStore},
+    };
+
+    /// In-memory SurrealDB store without the migrations applied.
+    async fn empty_store() -> Store {
+        let db = surrealdb::Surreal::new::<surrealdb::engine::local::Mem>(())
+            .await
+            .unwrap();
+        db.use_ns("revolut-test").use_db("revolut").await.unwrap();
+
+        Store::new(SurrealBirthdayStore::new(db))
+    }
+
+    /// Directory for the files of the test, removed before the test starts.
+    async fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("revolut-backup-{}", name));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_backup_file_name() {
+        let clock = FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap());
+        let backups = BackupDir::new(PathBuf::from("/backups"), 7, clock);
+
+        assert_eq!(
+            backups.next_file(),
+            PathBuf::from("/backups/backup-20240615T000000.000Z.surql")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = test_dir("roundtrip").await;
+        let file = dir.join("backup.surql");
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+
+        let source = Store::new_in_mem().await.unwrap();
+        source
+            .birthdays
+            .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+            .await
+            .unwrap();
+        let info = write_backup(&source, &file).await.unwrap();
+        assert_eq!(info.size, fs::metadata(&file).await.unwrap().len());
+        assert!(fs::read_to_string(checksum_path(&file))
+            .await
+            .unwrap()
+            .starts_with(&info.sha256));
+
+        // The store is migrated on creation, so it's not empty.
+        assert!(restore(&source, &file).await.is_err());
+
+        let target = empty_store().await;
+        restore(&target, &file).await.unwrap();
+        assert_eq!(
+            target.birthdays.get_birthday("foo").await.unwrap(),
+            Some(StoredBirthday {
+                birthday: Birthday::new(dob),
+                revision: 1,
+            })
+        );
+        assert_eq!(
+            target.schema.schema_version().await.unwrap(),
+            source.schema.schema_version().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_corrupted_backup() {
+        let dir = test_dir("corrupted").await;
+        let file = dir.join("backup.surql");
+
+        let source = Store::new_in_mem().await.unwrap();
+        write_backup(&source, &file).await.unwrap();
+        let mut content = fs::read(&file).await.unwrap();
+        content.extend_from_slice(b"DELETE birthday;\n");
+        fs::write(&file, content).await.unwrap();
+
+        let target = empty_store().await;
+        let err = restore(&target, &file).await.unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{}", err);
+        assert_eq!(target.schema.schema_version().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_backups_not_supported() {
+        let dir = test_dir("not-supported").await;
+        let clock = FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap());
+
+        let res = create_backup(
+            State(Store::new(InMemoryBirthdayStore::default())),
+            State(BackupDir::new(dir.clone(), 7, clock)),
+        )
+        .await;
+
+        assert!(matches!(
+            res,
+            Err(ApiError {
+                status: 501,
+                code: ErrorCode::BackupsNotSupported,
+                ..
+            })
+        ));
+        let mut files = fs::read_dir(&dir).await.unwrap();
+        assert!(files.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_backups() {
+        let dir = test_dir("rotate").await;
+        let store = Store::new_in_mem().await.unwrap();
+
+        let mut files = vec![];
+        for day in 15..18 {
+            let clock = FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, day).unwrap());
+            let backups = BackupDir::new(dir.clone(), 2, clock);
+            let (_, Json(info)) = create_backup(State(store.clone()), State(backups))
+                .await
+                .unwrap();
+            files.push(info.file);
+        }
+
+        assert!(!files[0].exists());
+        assert!(!checksum_path(&files[0]).exists());
+        for file in &files[1..] {
+            assert!(file.exists());
+            assert!(checksum_path(file).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_in_progress() {
+        let dir = test_dir("in-progress").await;
+        let clock = FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap());
+        let backups = BackupDir::new(dir.clone(), 7, clock);
+        let _writing = backups.writing.lock().await;
+
+        let res = create_backup(
+            State(Store::new_in_mem().await.unwrap()),
+            State(backups.clone()),
+        )
+        .await;
+
+        assert!(matches!(
+            res,
+            Err(ApiError {
+                status: 409,
+                code: ErrorCode::BackupInProgress,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+use chrono::{DateTime, Utc};
+#[cfg(test)]
+use chrono::{NaiveDate, NaiveTime};
+
+/// Source of the current time.
+/// The handlers get the clock from the axum state, so the tests can control the time.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Clock reading the system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock stopped at the given time.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FixedClock(pub DateTime<Utc>);
+
+#[cfg(test)]
+impl FixedClock {
+    /// Clock stopped at the midnight UTC of the given date.
+    pub(crate) fn on(date: NaiveDate) -> Self {
+        FixedClock(date.and_time(NaiveTime::MIN).and_utc())
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
@@ -1,7 +1,21 @@
-use axum::{body::Body, http::StatusCode, response::IntoResponse, Json};
+use std::str::FromStr;
+
+use axum::{
+    body::Body, extract::rejection::JsonRejection, extract::State, http::StatusCode,
+    response::IntoResponse, Json,
+};
+use log::LevelFilter;
 use prometheus::{Encoder, TextEncoder};
 use serde_json::json;
 
+use crate::{
+    app::{
+        api::{ApiError, ApiResult, ViolationCode},
+        health::checks::{Readiness, ReadinessReport, ReadinessStatus},
+    },
+    setup::Logger,
+};
+
 /// Serve the Prometheus metrics.
 pub async fn metrics() -> impl IntoResponse {
     let encoder = TextEncoder::new();
@@ -13,7 +27,100 @@ pub async fn metrics() -> impl IntoResponse {
     (StatusCode::OK, Body::from(buffer))
 }
 
-/// Health check endpoint.
+/// Liveness check endpoint, served as `/livez` and `/health`. The process is alive as
+/// long as it responds, the dependencies are checked by `readyz`.
 pub async fn health() -> impl IntoResponse {
     (StatusCode::OK, Json(json!({"status": "ok"})))
 }
+
+/// Readiness check endpoint. Runs all the registered checks and responds with
+/// `503 Service Unavailable` if any of them fails or the application is shutting down.
+pub(crate) async fn readyz(
+    State(readiness): State<Readiness>,
+) -> (StatusCode, Json<ReadinessReport>) {
+    let report = readiness.report().await;
+    let status = match report.status {
+        ReadinessStatus::Ready => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(report))
+}
+
+/// Log level of the running application.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub(crate) struct LogLevel {
+    /// One of `off`, `error`, `warn`, `info`, `debug`, `trace`.
+    pub level: String,
+}
+
+impl From<LevelFilter> for LogLevel {
+    fn from(level: LevelFilter) -> Self {
+        LogLevel {
+            level: level.as_str().to_lowercase(),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> ApiResult<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| {
+        ApiError::invalid_field(
+            "level",
+            ViolationCode::InvalidValue,
+            "The log level must be one of: off, error, warn, info, debug, trace",
+        )
+    })
+}
+
+/// Get the current log level.
+pub(crate) async fn get_log_level(State(logger): State<Logger>) -> Json<LogLevel> {
+    Json(logger.level().into())
+}
+
+/// Change the log level of the running application, e.g. to debug a single instance.
+/// The level is kept until the next change, reload or restart.
+pub(crate) async fn set_log_level(
+    State(logger): State<Logger>,
+    body: Result<Json<LogLevel>, JsonRejection>,
+) -> ApiResult<Json<LogLevel>> {
+    let Json(req) = body?;
+    let level = parse_level(&req.level)?;
+    logger.set_level(level)?;
+
+    Ok(Json(level.into()))
+}
+
+/// Load the configuration again and apply its log level, the same as sending `SIGHUP`
+/// to the process. The changes of the other settings are logged and ignored until
+/// the restart.
+pub(crate) async fn reload_config(State(logger): State<Logger>) -> ApiResult<Json<LogLevel>> {
+    let level = logger.reload().map_err(|err| {
+        log::warn!("Failed to reload the configuration: {:?}", err);
+        ApiError::from_status(StatusCode::UNPROCESSABLE_ENTITY, &format!("{:#}", err))
+    })?;
+
+    Ok(Json(level.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("debug").unwrap(), LevelFilter::Debug);
+        assert_eq!(parse_level("WARN").unwrap(), LevelFilter::Warn);
+        assert_eq!(parse_level("off").unwrap(), LevelFilter::Off);
+
+        let err = parse_level("verbose").unwrap_err();
+        assert_eq!(err.errors[0].field, "level");
+        assert_eq!(err.errors[0].code, ViolationCode::InvalidValue);
+
+        assert_eq!(
+            LogLevel::from(LevelFilter::Info),
+            LogLevel {
+                level: "info".to_owned()
+            }
+        );
+    }
+}
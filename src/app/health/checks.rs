@@ -0,0 +1,305 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use axum::async_trait;
+use tokio::task::JoinHandle;
+
+use crate::app::Store;
+
+/// Check of a single dependency required to serve the requests.
+#[async_trait]
+pub(crate) trait HealthCheck: Send + Sync {
+    /// Name of the check in the readiness report.
+    fn name(&self) -> &'static str;
+    /// Fail if the dependency is not usable.
+    async fn check(&self) -> Result<()>;
+}
+
+/// Runs a round-trip query against the storage.
+pub(crate) struct StoreCheck(pub Store);
+
+#[async_trait]
+impl HealthCheck for StoreCheck {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.0.birthdays.ping().await
+    }
+}
+
+/// Checks the free space on the file system of the directory, e.g. of the embedded
+/// database.
+pub(crate) struct DiskCheck {
+    pub dir: PathBuf,
+    /// Minimal number of bytes available to the application.
+    pub min_free: u64,
+}
+
+#[async_trait]
+impl HealthCheck for DiskCheck {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    async fn check(&self) -> Result<()> {
+        let dir = self.dir.clone();
+        let free = tokio::task::spawn_blocking(move || free_space(&dir)).await??;
+        if free < self.min_free {
+            bail!(
+                "Only {} bytes available in {}, at least {} required",
+                free,
+                self.dir.display(),
+                self.min_free
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn free_space(dir: &std::path::Path) -> Result<u64> {
+    let stat = rustix::fs::statvfs(dir)?;
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &std::path::Path) -> Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Checks the server task is still running, e.g. the main server didn't fail while
+/// the health server is still up.
+#[derive(Clone)]
+pub(crate) struct ServerCheck {
+    finished: Arc<AtomicBool>,
+}
+
+impl ServerCheck {
+    /// Watch the server task. The returned handle finishes along with the server and
+    /// should be awaited instead of the server's one.
+    pub(crate) fn watch(server: JoinHandle<()>) -> (Self, JoinHandle<()>) {
+        let finished = Arc::new(AtomicBool::new(false));
+        let check = ServerCheck {
+            finished: finished.clone(),
+        };
+        let handle = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("The server task failed: {}", err);
+            }
+            finished.store(true, Ordering::SeqCst);
+        });
+        (check, handle)
+    }
+}
+
+#[async_trait]
+impl HealthCheck for ServerCheck {
+    fn name(&self) -> &'static str {
+        "server"
+    }
+
+    async fn check(&self) -> Result<()> {
+        if self.finished.load(Ordering::SeqCst) {
+            bail!("The server is not running");
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReadinessStatus {
+    Ready,
+    NotReady,
+    /// The application is shutting down and doesn't accept new requests.
+    ShuttingDown,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CheckStatus {
+    Ok,
+    Failed,
+    TimedOut,
+}
+
+/// Result of a single check.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CheckReport {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Results of all the checks.
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct ReadinessReport {
+    pub status: ReadinessStatus,
+    pub checks: Vec<CheckReport>,
+}
+
+/// Registry of the checks deciding whether the application can serve the requests.
+#[derive(Clone)]
+pub(crate) struct Readiness {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    /// Time limit of each check, the slow dependency is as bad as the broken one.
+    timeout: Duration,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Readiness {
+            checks: vec![],
+            timeout,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn with_check(mut self, check: impl HealthCheck + 'static) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    /// Report not ready from now on, so no new requests are routed to the application.
+    pub(crate) fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Run all the checks concurrently.
+    pub(crate) async fn report(&self) -> ReadinessReport {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return ReadinessReport {
+                status: ReadinessStatus::ShuttingDown,
+                checks: vec![],
+            };
+        }
+
+        let checks =
+            futures::future::join_all(self.checks.iter().map(|check| self.run(check.as_ref())))
+                .await;
+        let status = if checks.iter().all(|check| check.status == CheckStatus::Ok) {
+            ReadinessStatus::Ready
+        } else {
+            ReadinessStatus::NotReady
+        };
+
+        ReadinessReport { status, checks }
+    }
+
+    async fn run(&self, check: &dyn HealthCheck) -> CheckReport {
+        let start = Instant::now();
+        let (status, error) = match tokio::time::timeout(self.timeout, check.check()).await {
+            Ok(Ok(())) => (CheckStatus::Ok, None),
+            Ok(Err(err)) => (CheckStatus::Failed, Some(format!("{:#}", err))),
+            Err(_) => (
+                CheckStatus::TimedOut,
+                Some(format!("Timed out after {:?}", self.timeout)),
+            ),
+        };
+        if let Some(error) = &error {
+            log::warn!("The {} health check failed: {}", check.name(), error);
+        }
+
+        CheckReport {
+            name: check.name(),
+            status,
+            duration_ms: start.elapsed().as_millis() as u64,
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeCheck {
+        name: &'static str,
+        delay: Duration,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl HealthCheck for FakeCheck {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn check(&self) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            if self.fail {
+                bail!("Broken");
+            }
+            Ok(())
+        }
+    }
+
+    fn fake(name: &'static str, delay_ms: u64, fail: bool) -> FakeCheck {
+        FakeCheck {
+            name,
+            delay: Duration::from_millis(delay_ms),
+            fail,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readiness_report() {
+        let readiness = Readiness::new(Duration::from_millis(100))
+            .with_check(fake("fast", 0, false))
+            .with_check(fake("slow", 1000, false));
+
+        let report = readiness.report().await;
+        assert_eq!(report.status, ReadinessStatus::NotReady);
+        assert_eq!(report.checks[0].status, CheckStatus::Ok);
+        assert_eq!(report.checks[1].name, "slow");
+        assert_eq!(report.checks[1].status, CheckStatus::TimedOut);
+
+        let readiness = readiness.with_check(fake("broken", 0, true));
+        let report = readiness.report().await;
+        assert_eq!(report.checks[2].status, CheckStatus::Failed);
+        assert_eq!(report.checks[2].error.as_deref(), Some("Broken"));
+
+        let readiness =
+            Readiness::new(Duration::from_millis(100)).with_check(fake("fast", 0, false));
+        assert_eq!(readiness.report().await.status, ReadinessStatus::Ready);
+
+        readiness.shut_down();
+        let report = readiness.report().await;
+        assert_eq!(report.status, ReadinessStatus::ShuttingDown);
+        assert!(report.checks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dependency_checks() {
+        let store = Store::new_in_mem().await.unwrap();
+        StoreCheck(store).check().await.unwrap();
+
+        let disk = DiskCheck {
+            dir: std::env::temp_dir(),
+            min_free: 0,
+        };
+        disk.check().await.unwrap();
+        let disk = DiskCheck {
+            min_free: u64::MAX,
+            ..disk
+        };
+        assert!(disk.check().await.is_err());
+
+        let (server, handle) = ServerCheck::watch(tokio::spawn(async {}));
+        handle.await.unwrap();
+        assert!(server.check().await.is_err());
+    }
+}
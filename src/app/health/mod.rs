@@ -1,2 +1,3 @@
 pub(crate) mod api;
+pub(crate) mod checks;
 pub(crate) mod middleware;
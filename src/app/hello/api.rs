@@ -1,20 +1,29 @@
-use anyhow::anyhow;
 use axum::body::Body;
-use axum::extract::State;
+use axum::extract::{Query, State};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
-use chrono::{Datelike, NaiveDate};
-
-use super::store::BirthdayStore;
+use chrono::{Days, NaiveDate};
+use chrono_tz::Tz;
+use fluent_bundle::FluentArgs;
+
+use super::birthday::{self, Calendar, NextBirthday};
+use super::cursor::Cursor;
+use super::etag::{Conditional, EntityTag, IfMatch, IfNoneMatch, Tagged};
+use super::format::{Formatted, ResponseFormat};
+use super::store::{day_of_year, Birthday, ListOrder};
 use super::validation::ValidatedUsername;
-use crate::app::api::{ApiError, ApiResult};
+use crate::app::api::{ApiError, ApiResult, ViolationCode};
+use crate::app::audit::{AuditAction, AuditEntry};
+use crate::app::i18n::{Localized, Messages};
 use crate::app::Store;
 
 #[derive(serde::Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct UserBirthdayRequest {
     pub date_of_birth: String,
+    /// IANA time zone name, e.g. `Europe/London`.
+    pub timezone: Option<String>,
 }
 
 impl UserBirthdayRequest {
@@ -22,6 +31,11 @@ impl UserBirthdayRequest {
         let date = chrono::NaiveDate::parse_from_str(&self.date_of_birth, "%Y-%m-%d")?;
         Ok(date)
     }
+
+    pub fn timezone(&self) -> anyhow::Result<Option<Tz>> {
+        let timezone = self.timezone.as_deref().map(str::parse).transpose()?;
+        Ok(timezone)
+    }
 }
 
 pub(crate) struct UserBirthdayResponse();
@@ -35,6 +49,31 @@ impl IntoResponse for UserBirthdayResponse {
 #[derive(serde::Serialize)]
 pub(crate) struct GetBirthdayResponse {
     message: String,
+    /// Machine-readable fields, returned only in the detailed format.
+    #[serde(flatten)]
+    details: Option<BirthdayDetails>,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BirthdayDetails {
+    date_of_birth: NaiveDate,
+    next_birthday: NaiveDate,
+    days_until_birthday: i64,
+    is_birthday_today: bool,
+    age: u32,
+}
+
+impl BirthdayDetails {
+    fn new(birthday: &Birthday, next: &NextBirthday) -> Self {
+        BirthdayDetails {
+            date_of_birth: birthday.dob,
+            next_birthday: next.date,
+            days_until_birthday: next.days_until,
+            is_birthday_today: next.days_until == 0,
+            age: next.age,
+        }
+    }
 }
 
 impl GetBirthdayResponse {
@@ -42,95 +81,419 @@ impl GetBirthdayResponse {
     ///
     /// The response message will be different depending on the user's birthday.
     ///   - If the birthday is today, the message will be "Hello, {username}! Happy birthday!".
-    ///   - Otherwise, the message will be "Hello, {username}! Your birthday is in {days_until_birthday} day(s)".
-    pub fn new(username: &str, dob: &chrono::NaiveDate) -> anyhow::Result<Self> {
-        let now: NaiveDate = chrono::Local::now().date_naive();
+    ///   - Otherwise, the message will be "Hello, {username}! Your birthday is in {days_until_birthday} days".
+    ///
+    /// The message is translated to the locale negotiated with the client.
+    pub fn new(messages: &Messages, username: &str, days_until_birthday: i64) -> Self {
+        let mut args = FluentArgs::new();
+        args.set("username", username);
 
-        let dob = Self::this_year(dob)?;
-        let message = if dob == now {
-            format!("Hello, {}! Happy birthday!", username)
+        let message = if days_until_birthday == 0 {
+            messages.format("birthday-today", &args)
         } else {
-            let mut days_until_birthday = dob.signed_duration_since(now).num_days();
-            if days_until_birthday < 0 {
-                let birthday_next_year = Self::next_year(&dob)?;
-                days_until_birthday = birthday_next_year.signed_duration_since(now).num_days();
-            }
-            format!(
-                "Hello, {}! Your birthday is in {} day(s)",
-                username, days_until_birthday
-            )
+            args.set("days", days_until_birthday);
+            messages.format("birthday-in-days", &args)
         };
 
-        Ok(GetBirthdayResponse { message })
+        GetBirthdayResponse {
+            message,
+            details: None,
+        }
     }
+}
 
-    fn this_year(date: &chrono::NaiveDate) -> anyhow::Result<chrono::NaiveDate> {
-        let now: NaiveDate = chrono::Local::now().date_naive();
-        if let Some(date) = date.with_year(now.year()) {
-            Ok(date)
-        } else {
-            Err(anyhow!("Failed to set the year to the current year"))
-        }
+/// Default number of the users listed on a single page.
+const DEFAULT_PAGE_LIMIT: usize = 20;
+
+/// Maximum number of the users listed on a single page.
+const MAX_PAGE_LIMIT: usize = 100;
+
+/// Sort order of the listed users.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ListSort {
+    /// Alphabetically by the username.
+    Username,
+    /// By the next upcoming birthday, starting from today.
+    Upcoming,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub(crate) struct ListUsersQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<ListSort>,
+}
+
+impl ListUsersQuery {
+    /// Resolve the order of the listing, continuing after the cursor if there is one.
+    fn order(&self, today: &NaiveDate) -> ApiResult<ListOrder> {
+        let cursor = self.cursor.as_deref().map(Cursor::decode).transpose()?;
+
+        let order = match (cursor, self.sort.unwrap_or(ListSort::Username)) {
+            (None, ListSort::Username) => ListOrder::Username { after: None },
+            (None, ListSort::Upcoming) => ListOrder::Upcoming {
+                from: day_of_year(today),
+                after: None,
+            },
+            (Some(cursor), sort) => {
+                let order = cursor.into_order();
+                let matches = matches!(
+                    (&order, sort),
+                    (ListOrder::Username { .. }, ListSort::Username)
+                        | (ListOrder::Upcoming { .. }, ListSort::Upcoming)
+                );
+                // The sort can be omitted when the cursor is passed.
+                if self.sort.is_some() && !matches {
+                    return Err(ApiError::invalid_field(
+                        "cursor",
+                        ViolationCode::InvalidValue,
+                        "The cursor was created for a different sort order.",
+                    ));
+                }
+                order
+            }
+        };
+
+        Ok(order)
     }
 
-    fn next_year(date: &chrono::NaiveDate) -> anyhow::Result<chrono::NaiveDate> {
-        let now: NaiveDate = chrono::Local::now().date_naive();
-        if let Some(date) = date.with_year(now.year() + 1) {
-            Ok(date)
-        } else {
-            Err(anyhow!("Failed to set the year to the next year"))
+    fn limit(&self) -> ApiResult<usize> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_LIMIT) {
+            limit @ 1..=MAX_PAGE_LIMIT => Ok(limit),
+            _ => Err(ApiError::invalid_field(
+                "limit",
+                ViolationCode::OutOfRange,
+                &format!(
+                    "Invalid limit. The limit should be between 1 and {}.",
+                    MAX_PAGE_LIMIT
+                ),
+            )),
         }
     }
 }
 
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserBirthdayItem {
+    username: String,
+    date_of_birth: NaiveDate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<Tz>,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListUsersResponse {
+    items: Vec<UserBirthdayItem>,
+    /// Cursor of the next page, `None` if this is the last page.
+    next_cursor: Option<String>,
+}
+
+/// Default window of the upcoming birthdays query, in days.
+const DEFAULT_UPCOMING_DAYS: i64 = 7;
+
+/// Maximum window of the upcoming birthdays query, in days. It covers the whole year.
+const MAX_UPCOMING_DAYS: i64 = 366;
+
+/// Number of the records fetched from the store at once by the upcoming birthdays query.
+const UPCOMING_BATCH_SIZE: usize = 100;
+
+/// Maximum difference between the current dates in any two time zones, in days.
+const MAX_TIMEZONE_SKEW_DAYS: u64 = 2;
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub(crate) struct UpcomingBirthdaysQuery {
+    pub days: Option<i64>,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpcomingBirthday {
+    username: String,
+    date_of_birth: NaiveDate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<Tz>,
+    days_until_birthday: i64,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpcomingBirthdaysResponse {
+    days: i64,
+    users: Vec<UpcomingBirthday>,
+}
+
 /// API handler for upserting the day of birth for the requested user.
 /// If the user doesn't exist, the handler will create a new record in the database.
+/// With the `If-Match` header, the existing record is only replaced if it still has
+/// the revision the client has read, otherwise the handler will return a 412.
+/// The new revision is returned in the `ETag` header.
 pub(crate) async fn upsert_user(
     State(store): State<Store>,
     ValidatedUsername(username): ValidatedUsername,
+    IfMatch(if_match): IfMatch,
     req: UserBirthdayRequest,
-) -> ApiResult<UserBirthdayResponse> {
+) -> ApiResult<Tagged<UserBirthdayResponse>> {
     log::debug!(
-        "Upserting user birthday. Username: {}, dob: {}",
+        "Upserting user birthday. Username: {}, dob: {}, timezone: {:?}",
         &username,
-        &req.date_of_birth
+        &req.date_of_birth,
+        &req.timezone
     );
 
-    let dob = req.dob()?;
-    store.upsert_birthday(username, dob).await?;
-
-    Ok(UserBirthdayResponse())
+    let birthday = Birthday {
+        dob: req.dob()?,
+        timezone: req.timezone()?,
+    };
+    let revision = match if_match {
+        None => store.birthdays.upsert_birthday(username, birthday).await?,
+        Some(expected) => store
+            .birthdays
+            .update_birthday(&username, birthday, &expected)
+            .await?
+            .ok_or_else(|| ApiError::precondition_failed(&username))?,
+    };
+
+    Ok(Tagged(revision, UserBirthdayResponse()))
 }
 
 /// API handler for getting the birthday for the requested user.
+/// The detailed format adds the machine-readable fields to the greeting message.
 /// If the user doesn't exist, the handler will return a 404.
+/// The revision of the record is returned in the `ETag` header. If the client already
+/// has it, as listed in the `If-None-Match` header, the handler will return a 304.
 pub(crate) async fn get_birthday(
     State(store): State<Store>,
+    State(calendar): State<Calendar>,
     ValidatedUsername(username): ValidatedUsername,
-) -> ApiResult<Json<GetBirthdayResponse>> {
-    log::debug!("Getting birthday for user: {}", &username);
-    let birthday = store.get_birthday(&username).await?;
+    if_none_match: IfNoneMatch,
+    format: ResponseFormat,
+    messages: Messages,
+) -> ApiResult<Localized<Formatted<Conditional<Json<GetBirthdayResponse>>>>> {
+    log::debug!(
+        "Getting birthday for user: {}, locale: {}",
+        &username,
+        messages.locale()
+    );
+    let Some(record) = store.birthdays.get_birthday(&username).await? else {
+        return Err(ApiError::user_not_found(&username));
+    };
+
+    // The message changes with the user's date, the locale and the format, so each
+    // of them has its own tag.
+    let birthday = record.birthday;
+    let today = calendar.today_in(birthday.timezone);
+    let tag = EntityTag::variant(
+        record.revision,
+        format!("{}-{}-{}", today, messages.locale(), format.as_str()),
+    );
+    if if_none_match.matches(&tag) {
+        return Ok(Localized(
+            messages.locale().clone(),
+            Formatted(format, Conditional::NotModified(tag)),
+        ));
+    }
 
-    if let Some(birthday) = birthday {
-        let response = GetBirthdayResponse::new(&username, &birthday.dob)?;
-        Ok(Json(response))
+    let next = calendar.next_birthday(&birthday);
+    let mut response = GetBirthdayResponse::new(&messages, &username, next.days_until);
+    if format == ResponseFormat::Detailed {
+        response.details = Some(BirthdayDetails::new(&birthday, &next));
+    }
+
+    let modified = Conditional::Modified(tag, Json(response));
+    Ok(Localized(
+        messages.locale().clone(),
+        Formatted(format, modified),
+    ))
+}
+
+/// API handler for listing the users' birthdays.
+/// The users are paginated with a cursor, the `nextCursor` of the response should be
+/// passed as the `cursor` query parameter to get the next page.
+pub(crate) async fn list_users(
+    State(store): State<Store>,
+    State(calendar): State<Calendar>,
+    Query(query): Query<ListUsersQuery>,
+) -> ApiResult<Json<ListUsersResponse>> {
+    let (order, limit) = match (query.order(&calendar.today()), query.limit()) {
+        (Ok(order), Ok(limit)) => (order, limit),
+        // Report all the invalid parameters at once.
+        (order, limit) => {
+            let errors = [order.err(), limit.err()]
+                .into_iter()
+                .flatten()
+                .flat_map(|err| err.errors)
+                .collect();
+            return Err(ApiError::validation(errors));
+        }
+    };
+    log::debug!("Listing users. Order: {:?}, limit: {}", &order, limit);
+
+    // Fetch one more record to find out if there is a next page.
+    let mut page = store.birthdays.list_birthdays(&order, limit + 1).await?;
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|last| Cursor::after(&order, last).encode())
     } else {
-        Err(ApiError::not_found(&format!(
-            "User '{}' was not found",
-            &username
-        )))
+        None
+    };
+
+    let items = page
+        .into_iter()
+        .map(|b| UserBirthdayItem {
+            username: b.username,
+            date_of_birth: b.birthday.dob,
+            timezone: b.birthday.timezone,
+        })
+        .collect();
+
+    Ok(Json(ListUsersResponse { items, next_cursor }))
+}
+
+/// API handler for finding the users whose next birthday is within the requested
+/// number of days. Users are ordered by the number of days until their birthday,
+/// counted in their time zones.
+pub(crate) async fn upcoming_birthdays(
+    State(store): State<Store>,
+    State(calendar): State<Calendar>,
+    Query(query): Query<UpcomingBirthdaysQuery>,
+) -> ApiResult<Json<UpcomingBirthdaysResponse>> {
+    let days = query.days.unwrap_or(DEFAULT_UPCOMING_DAYS);
+    if !(0..=MAX_UPCOMING_DAYS).contains(&days) {
+        return Err(ApiError::invalid_field(
+            "days",
+            ViolationCode::OutOfRange,
+            &format!(
+                "Invalid number of days. The number should be between 0 and {}.",
+                MAX_UPCOMING_DAYS
+            ),
+        ));
+    }
+    log::debug!("Getting birthdays in the next {} day(s)", days);
+
+    // The users' dates may be a couple of days off the server's date, so the scan
+    // starts that many days earlier and ends that many days later.
+    let skew = Days::new(MAX_TIMEZONE_SKEW_DAYS);
+    let from = calendar.today() - skew;
+    let mut order = ListOrder::Upcoming {
+        from: day_of_year(&from),
+        after: None,
+    };
+    let mut users = vec![];
+
+    // The store lists the birthdays by the day of the year starting from `from`, which
+    // is the order of the days until the birthday. Stop at the first one out of the window.
+    'pages: loop {
+        let page = store
+            .birthdays
+            .list_birthdays(&order, UPCOMING_BATCH_SIZE)
+            .await?;
+        let is_last_page = page.len() < UPCOMING_BATCH_SIZE;
+
+        for b in &page {
+            let days_from =
+                birthday::days_until_birthday(&b.birthday.dob, &from, calendar.leap_day());
+            if days_from > days + 2 * MAX_TIMEZONE_SKEW_DAYS as i64 {
+                break 'pages;
+            }
+
+            let days_until_birthday = calendar.days_until_birthday(&b.birthday);
+            if days_until_birthday <= days {
+                users.push(UpcomingBirthday {
+                    username: b.username.clone(),
+                    date_of_birth: b.birthday.dob,
+                    timezone: b.birthday.timezone,
+                    days_until_birthday,
+                });
+            }
+        }
+
+        match page.last() {
+            Some(last) if !is_last_page => {
+                order = ListOrder::Upcoming {
+                    from: day_of_year(&from),
+                    after: Some((day_of_year(&last.birthday.dob), last.username.clone())),
+                };
+            }
+            _ => break,
+        }
+    }
+
+    // The birthdays are listed in the order of the server's date, the users in the
+    // other time zones might be a day or two off.
+    users.sort_by(|a, b| {
+        (a.days_until_birthday, &a.username).cmp(&(b.days_until_birthday, &b.username))
+    });
+
+    Ok(Json(UpcomingBirthdaysResponse { days, users }))
+}
+
+/// API handler for erasing the data of the requested user.
+/// The date of birth is removed from the database, only the audit entry without
+/// the date of birth is retained. If the user doesn't exist, the handler will return a 404.
+pub(crate) async fn delete_user(
+    State(store): State<Store>,
+    ValidatedUsername(username): ValidatedUsername,
+) -> ApiResult<UserBirthdayResponse> {
+    let audit_entry = AuditEntry::for_current_request(AuditAction::Erase, &username);
+
+    log::debug!("Deleting user: {}", &username);
+    if !store
+        .birthdays
+        .delete_birthday(&username, audit_entry)
+        .await?
+    {
+        return Err(ApiError::user_not_found(&username));
     }
+    log::info!("Erased the data of user: {}", &username);
+
+    Ok(UserBirthdayResponse())
 }
 
 #[cfg(test)]
 mod tests {
 
+    use std::sync::Arc;
+
     use super::*;
+    use crate::app::clock::FixedClock;
+    use crate::app::hello::birthday::LeapDayPolicy;
+    use crate::app::hello::etag::ExpectedTags;
+    use crate::app::hello::store::{ExpectedRevision, InMemoryBirthdayStore, StoredBirthday};
+    use crate::app::i18n::Catalog;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    /// Messages negotiated for the `Accept-Language` header, falling back to English.
+    fn messages(accept_language: Option<&str>) -> Messages {
+        Catalog::new("en".parse().unwrap())
+            .unwrap()
+            .negotiate(accept_language)
+    }
+
+    /// Unwrap the body of the response which is not `304 Not Modified`.
+    fn modified<T>(
+        Localized(_, Formatted(_, res)): Localized<Formatted<Conditional<Json<T>>>>,
+    ) -> T {
+        match res {
+            Conditional::Modified(_, Json(body)) => body,
+            Conditional::NotModified(_) => panic!("The response is not modified"),
+        }
+    }
+
+    /// Calendar with the clock stopped at the given date.
+    fn calendar(today: &str) -> Calendar {
+        Calendar::new(FixedClock::on(date(today)), LeapDayPolicy::Feb28, Tz::UTC)
+    }
 
     #[tokio::test]
     async fn test_user_birthday_request_dob() {
         let req = UserBirthdayRequest {
             date_of_birth: "2021-01-01".to_string(),
+            timezone: None,
         };
 
         let res = req.dob();
@@ -146,100 +509,375 @@ mod tests {
         let store = Store::new_in_mem().await.unwrap();
 
         let res = upsert_user(
-            State(store),
+            State(store.clone()),
             ValidatedUsername("foo".to_owned()),
+            IfMatch(None),
             UserBirthdayRequest {
                 date_of_birth: "2021-01-01".to_owned(),
+                timezone: Some("Asia/Tokyo".to_owned()),
             },
         )
         .await;
 
-        assert!(res.is_ok());
+        assert!(matches!(res, Ok(Tagged(1, _))));
+        assert_eq!(
+            store.birthdays.get_birthday("foo").await.unwrap(),
+            Some(StoredBirthday {
+                birthday: Birthday {
+                    dob: date("2021-01-01"),
+                    timezone: Some(Tz::Asia__Tokyo),
+                },
+                revision: 1,
+            })
+        );
     }
 
     #[tokio::test]
-    async fn test_get_user_birthday() {
-        let this_year = chrono::Local::now().naive_local().date().year();
-        let tomorrow_last_year = chrono::Local::now()
-            .naive_local()
-            .date()
-            .succ_opt()
-            .unwrap()
-            .with_year(this_year - 1)
-            .unwrap();
+    async fn test_upsert_user_birthday_with_if_match() {
+        let store = Store::new_in_mem().await.unwrap();
+        let upsert = |if_match: Option<ExpectedRevision>| {
+            upsert_user(
+                State(store.clone()),
+                ValidatedUsername("foo".to_owned()),
+                IfMatch(if_match),
+                UserBirthdayRequest {
+                    date_of_birth: "2021-01-01".to_owned(),
+                    timezone: None,
+                },
+            )
+        };
+
+        // The record must exist.
+        let res = upsert(Some(ExpectedRevision::Any)).await;
+        assert!(matches!(res, Err(ApiError { status: 412, .. })));
+
+        assert!(matches!(upsert(None).await, Ok(Tagged(1, _))));
+        assert!(matches!(upsert(None).await, Ok(Tagged(2, _))));
+
+        let res = upsert(Some(ExpectedRevision::OneOf(vec![1]))).await;
+        assert!(matches!(res, Err(ApiError { status: 412, .. })));
 
+        let res = upsert(Some(ExpectedRevision::OneOf(vec![2]))).await;
+        assert!(matches!(res, Ok(Tagged(3, _))));
+    }
+
+    #[tokio::test]
+    async fn test_get_user_birthday() {
         let store = Store::new_in_mem().await.unwrap();
         store
-            .upsert_birthday("foo".to_owned(), tomorrow_last_year)
+            .birthdays
+            .upsert_birthday("foo".to_owned(), Birthday::new(date("2000-06-16")))
             .await
             .unwrap();
 
-        let res = get_birthday(State(store), ValidatedUsername("foo".to_owned())).await;
+        let res = get_birthday(
+            State(store),
+            State(calendar("2024-06-15")),
+            ValidatedUsername("foo".to_owned()),
+            IfNoneMatch(None),
+            ResponseFormat::Message,
+            messages(None),
+        )
+        .await;
 
         assert!(res.is_ok());
 
         if let Ok(res) = res {
-            assert_eq!(res.message, "Hello, foo! Your birthday is in 1 day(s)");
+            assert_eq!(
+                modified(res).message,
+                "Hello, foo! Your birthday is in 1 day"
+            );
         }
     }
 
     #[tokio::test]
-    async fn test_get_birthday_response_with_one_day_until_birthday() {
-        let this_year = chrono::Local::now().naive_local().date().year();
-        let tomorrow_last_year = chrono::Local::now()
-            .naive_local()
-            .date()
-            .succ_opt()
-            .unwrap()
-            .with_year(this_year - 1)
+    async fn test_get_user_birthday_born_on_leap_day() {
+        let store = Store::new_in_mem().await.unwrap();
+        store
+            .birthdays
+            .upsert_birthday("foo".to_owned(), Birthday::new(date("2000-02-29")))
+            .await
             .unwrap();
 
-        let res = GetBirthdayResponse::new("foo", &tomorrow_last_year);
+        let res = get_birthday(
+            State(store),
+            State(calendar("2023-02-28")),
+            ValidatedUsername("foo".to_owned()),
+            IfNoneMatch(None),
+            ResponseFormat::Message,
+            messages(None),
+        )
+        .await;
 
         assert!(res.is_ok());
 
         if let Ok(res) = res {
-            assert_eq!(res.message, "Hello, foo! Your birthday is in 1 day(s)");
+            assert_eq!(modified(res).message, "Hello, foo! Happy birthday!");
         }
     }
 
     #[tokio::test]
-    async fn test_get_birthday_response_with_birthday_today() {
-        let this_year = chrono::Local::now().naive_local().date().year();
-        let today_last_year = chrono::Local::now()
-            .naive_local()
-            .date()
-            .with_year(this_year - 1)
+    async fn test_get_user_birthday_detailed() {
+        let store = Store::new_in_mem().await.unwrap();
+        store
+            .birthdays
+            .upsert_birthday("foo".to_owned(), Birthday::new(date("2000-06-16")))
+            .await
             .unwrap();
 
-        let res = GetBirthdayResponse::new("foo", &today_last_year);
+        let res = get_birthday(
+            State(store),
+            State(calendar("2024-06-15")),
+            ValidatedUsername("foo".to_owned()),
+            IfNoneMatch(None),
+            ResponseFormat::Detailed,
+            messages(None),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(modified(res)).unwrap(),
+            serde_json::json!({
+                "message": "Hello, foo! Your birthday is in 1 day",
+                "dateOfBirth": "2000-06-16",
+                "nextBirthday": "2024-06-16",
+                "daysUntilBirthday": 1,
+                "isBirthdayToday": false,
+                "age": 23,
+            })
+        );
+    }
 
-        assert!(res.is_ok());
+    #[tokio::test]
+    async fn test_get_user_birthday_not_modified() {
+        let store = Store::new_in_mem().await.unwrap();
+        let revision = store
+            .birthdays
+            .upsert_birthday("foo".to_owned(), Birthday::new(date("2000-06-16")))
+            .await
+            .unwrap();
 
-        if let Ok(res) = res {
-            assert_eq!(res.message, "Hello, foo! Happy birthday!");
-        }
+        let get = |today: &'static str, if_none_match: Option<EntityTag>| {
+            get_birthday(
+                State(store.clone()),
+                State(calendar(today)),
+                ValidatedUsername("foo".to_owned()),
+                IfNoneMatch(if_none_match.map(|tag| ExpectedTags::OneOf(vec![tag]))),
+                ResponseFormat::Message,
+                messages(None),
+            )
+        };
+
+        let Localized(_, Formatted(_, res)) = get("2024-06-15", None).await.unwrap();
+        let Conditional::Modified(tag, _) = res else {
+            panic!("The response is not modified");
+        };
+        assert_eq!(
+            tag,
+            EntityTag::variant(revision, "2024-06-15-en-message".to_owned())
+        );
+
+        let Localized(_, Formatted(_, res)) = get("2024-06-15", Some(tag.clone())).await.unwrap();
+        assert!(matches!(res, Conditional::NotModified(ref t) if *t == tag));
+
+        // The message changes the next day.
+        let Localized(_, Formatted(_, res)) = get("2024-06-16", Some(tag)).await.unwrap();
+        assert!(matches!(res, Conditional::Modified(..)));
+
+        let stale = EntityTag::variant(revision - 1, "2024-06-15-en-message".to_owned());
+        let Localized(_, Formatted(_, res)) = get("2024-06-15", Some(stale)).await.unwrap();
+        assert!(matches!(res, Conditional::Modified(..)));
+    }
+
+    #[tokio::test]
+    async fn test_get_birthday_response_body() {
+        let res = GetBirthdayResponse::new(&messages(None), "foo", 1);
+
+        assert_eq!(
+            serde_json::to_value(res).unwrap(),
+            serde_json::json!({ "message": "Hello, foo! Your birthday is in 1 day" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_birthday_response_with_one_day_until_birthday() {
+        let res = GetBirthdayResponse::new(&messages(None), "foo", 1);
+
+        assert_eq!(res.message, "Hello, foo! Your birthday is in 1 day");
+    }
+
+    #[tokio::test]
+    async fn test_get_birthday_response_in_requested_language() {
+        let res = GetBirthdayResponse::new(&messages(Some("pl-PL, en;q=0.5")), "foo", 3);
+        assert_eq!(res.message, "Cześć, foo! Twoje urodziny są za 3 dni");
+
+        let res = GetBirthdayResponse::new(&messages(Some("de")), "foo", 0);
+        assert_eq!(res.message, "Hallo, foo! Alles Gute zum Geburtstag!");
+    }
+
+    #[tokio::test]
+    async fn test_get_birthday_response_with_birthday_today() {
+        let res = GetBirthdayResponse::new(&messages(None), "foo", 0);
+
+        assert_eq!(res.message, "Hello, foo! Happy birthday!");
     }
 
     #[tokio::test]
     async fn test_get_birthday_response_with_birthday_already_passed() {
-        let this_year = chrono::Local::now().naive_local().date().year();
-        let yesterday_last_year = chrono::Local::now()
-            .naive_local()
-            .date()
-            .pred_opt()
-            .unwrap()
-            .with_year(this_year - 1)
-            .unwrap();
+        let days_until_birthday =
+            calendar("2024-06-15").days_until_birthday(&Birthday::new(date("2000-06-14")));
 
-        let res = GetBirthdayResponse::new("foo", &yesterday_last_year);
+        let res = GetBirthdayResponse::new(&messages(None), "foo", days_until_birthday);
 
+        assert_eq!(res.message, "Hello, foo! Your birthday is in 364 days");
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let backend = Arc::new(InMemoryBirthdayStore::default());
+        let store = Store {
+            birthdays: backend.clone(),
+            idempotency: backend.clone(),
+            schema: backend.clone(),
+            backup: backend.clone(),
+        };
+        store
+            .birthdays
+            .upsert_birthday(
+                "foo".to_owned(),
+                Birthday::new(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()),
+            )
+            .await
+            .unwrap();
+
+        let res = delete_user(State(store.clone()), ValidatedUsername("foo".to_owned())).await;
         assert!(res.is_ok());
+        assert!(store.birthdays.get_birthday("foo").await.unwrap().is_none());
+
+        let res = delete_user(State(store), ValidatedUsername("foo".to_owned())).await;
+        assert!(matches!(res, Err(ApiError { status: 404, .. })));
+
+        assert_eq!(
+            backend.audit_entries(),
+            vec![AuditEntry {
+                action: AuditAction::Erase,
+                username: "foo".to_owned(),
+                request_id: None,
+            }]
+        );
+    }
 
-        if let Ok(res) = res {
-            assert!(res
-                .message
-                .starts_with("Hello, foo! Your birthday is in 36"));
+    #[tokio::test]
+    async fn test_list_users() {
+        let store = Store::new_in_mem().await.unwrap();
+        for username in ["foo", "bar", "baz"] {
+            store
+                .birthdays
+                .upsert_birthday(
+                    username.to_owned(),
+                    Birthday::new(NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()),
+                )
+                .await
+                .unwrap();
         }
+
+        let query = ListUsersQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let Json(page) = list_users(
+            State(store.clone()),
+            State(calendar("2024-06-15")),
+            Query(query),
+        )
+        .await
+        .unwrap();
+        let usernames: Vec<_> = page.items.iter().map(|i| i.username.as_str()).collect();
+        assert_eq!(usernames, vec!["bar", "baz"]);
+        assert!(page.next_cursor.is_some());
+
+        let query = ListUsersQuery {
+            limit: Some(2),
+            cursor: page.next_cursor,
+            sort: None,
+        };
+        let Json(page) = list_users(State(store), State(calendar("2024-06-15")), Query(query))
+            .await
+            .unwrap();
+        let usernames: Vec<_> = page.items.iter().map(|i| i.username.as_str()).collect();
+        assert_eq!(usernames, vec!["foo"]);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_users_with_invalid_query() {
+        let store = Store::new_in_mem().await.unwrap();
+
+        let query = ListUsersQuery {
+            limit: Some(MAX_PAGE_LIMIT + 1),
+            ..Default::default()
+        };
+        let res = list_users(
+            State(store.clone()),
+            State(calendar("2024-06-15")),
+            Query(query),
+        )
+        .await;
+        assert!(matches!(res, Err(ApiError { status: 400, .. })));
+
+        let cursor = Cursor::Username {
+            after: "foo".to_owned(),
+        };
+        let query = ListUsersQuery {
+            cursor: Some(cursor.encode()),
+            sort: Some(ListSort::Upcoming),
+            ..Default::default()
+        };
+        let res = list_users(State(store), State(calendar("2024-06-15")), Query(query)).await;
+        assert!(matches!(res, Err(ApiError { status: 400, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_upcoming_birthdays() {
+        let store = Store::new_in_mem().await.unwrap();
+        for (username, dob, timezone) in [
+            ("foo", "1990-06-16", None),
+            ("bar", "2000-06-15", None),
+            ("baz", "1980-06-25", None),
+            // Still June 14 in New York, when it's already June 15 in UTC.
+            ("qux", "1995-06-14", Some(Tz::America__New_York)),
+        ] {
+            let birthday = Birthday {
+                dob: date(dob),
+                timezone,
+            };
+            store
+                .birthdays
+                .upsert_birthday(username.to_owned(), birthday)
+                .await
+                .unwrap();
+        }
+
+        let query = UpcomingBirthdaysQuery { days: Some(7) };
+        let Json(res) = upcoming_birthdays(
+            State(store.clone()),
+            State(calendar("2024-06-15")),
+            Query(query),
+        )
+        .await
+        .unwrap();
+
+        let users: Vec<_> = res
+            .users
+            .iter()
+            .map(|u| (u.username.as_str(), u.days_until_birthday))
+            .collect();
+        assert_eq!(users, vec![("bar", 0), ("qux", 0), ("foo", 1)]);
+
+        let query = UpcomingBirthdaysQuery { days: Some(-1) };
+        let res =
+            upcoming_birthdays(State(store), State(calendar("2024-06-15")), Query(query)).await;
+        assert!(matches!(res, Err(ApiError { status: 400, .. })));
     }
 }
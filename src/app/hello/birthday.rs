@@ -0,0 +1,309 @@
+use std::sync::Arc;
+
+use chrono::{Datelike, NaiveDate};
+use chrono_tz::Tz;
+
+use super::store::Birthday;
+use crate::app::clock::Clock;
+
+/// Day on which the birthday of the people born on February 29 is celebrated in the
+/// common years.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum LeapDayPolicy {
+    /// February 28, the last day of February.
+    #[default]
+    Feb28,
+    /// March 1, the day after February 28.
+    Mar1,
+}
+
+/// Find the birthday of the person born on `dob` in the given year.
+pub(crate) fn birthday_in_year(dob: &NaiveDate, year: i32, policy: LeapDayPolicy) -> NaiveDate {
+    if let Some(birthday) = dob.with_year(year) {
+        return birthday;
+    }
+
+    // Only February 29 doesn't exist in every year.
+    match policy {
+        LeapDayPolicy::Feb28 => NaiveDate::from_ymd_opt(year, 2, 28),
+        LeapDayPolicy::Mar1 => NaiveDate::from_ymd_opt(year, 3, 1),
+    }
+    .expect("February 28 and March 1 exist in every year")
+}
+
+/// Find the next birthday of the person born on `dob`, counting from `today`.
+/// Returns `today` if the birthday is today.
+pub(crate) fn next_birthday(
+    dob: &NaiveDate,
+    today: &NaiveDate,
+    policy: LeapDayPolicy,
+) -> NaiveDate {
+    let birthday = birthday_in_year(dob, today.year(), policy);
+    if birthday >= *today {
+        birthday
+    } else {
+        birthday_in_year(dob, today.year() + 1, policy)
+    }
+}
+
+/// Count the days from `today` until the next birthday of the person born on `dob`.
+/// Returns `0` if the birthday is today.
+pub(crate) fn days_until_birthday(
+    dob: &NaiveDate,
+    today: &NaiveDate,
+    policy: LeapDayPolicy,
+) -> i64 {
+    next_birthday(dob, today, policy)
+        .signed_duration_since(*today)
+        .num_days()
+}
+
+/// Count the full years the person born on `dob` has lived on `today`.
+/// The people born on February 29 get a year older on the day given by the policy.
+pub(crate) fn age(dob: &NaiveDate, today: &NaiveDate, policy: LeapDayPolicy) -> u32 {
+    let years = today.year() - dob.year();
+    let years = if birthday_in_year(dob, today.year(), policy) > *today {
+        years - 1
+    } else {
+        years
+    };
+
+    u32::try_from(years).unwrap_or_default()
+}
+
+/// Next birthday of the user, as seen on the current date in the user's time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NextBirthday {
+    pub date: NaiveDate,
+    pub days_until: i64,
+    /// Age of the user today.
+    pub age: u32,
+}
+
+/// Birthday calculations relative to the current date of the clock.
+/// It is shared with the handlers through the axum state.
+#[derive(Clone)]
+pub(crate) struct Calendar {
+    clock: Arc<dyn Clock>,
+    leap_day: LeapDayPolicy,
+    /// Time zone of the users who didn't set their own.
+    default_timezone: Tz,
+}
+
+impl Calendar {
+    pub(crate) fn new(
+        clock: impl Clock + 'static,
+        leap_day: LeapDayPolicy,
+        default_timezone: Tz,
+    ) -> Self {
+        Calendar {
+            clock: Arc::new(clock),
+            leap_day,
+            default_timezone,
+        }
+    }
+
+    pub(crate) fn leap_day(&self) -> LeapDayPolicy {
+        self.leap_day
+    }
+
+    /// Current date in the default time zone.
+    pub(crate) fn today(&self) -> NaiveDate {
+        self.today_in(None)
+    }
+
+    /// Current date in the given time zone, or in the default one if there is none.
+    pub(crate) fn today_in(&self, timezone: Option<Tz>) -> NaiveDate {
+        let timezone = timezone.unwrap_or(self.default_timezone);
+        self.clock.now().with_timezone(&timezone).date_naive()
+    }
+
+    /// Count the days until the user's next birthday, in the user's time zone.
+    pub(crate) fn days_until_birthday(&self, birthday: &Birthday) -> i64 {
+        self.next_birthday(birthday).days_until
+    }
+
+    /// Find the user's next birthday, in the user's time zone.
+    pub(crate) fn next_birthday(&self, birthday: &Birthday) -> NextBirthday {
+        let today = self.today_in(birthday.timezone);
+        let date = next_birthday(&birthday.dob, &today, self.leap_day);
+
+        NextBirthday {
+            date,
+            days_until: date.signed_duration_since(today).num_days(),
+            age: age(&birthday.dob, &today, self.leap_day),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Days;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::app::clock::FixedClock;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    /// Any date between the years 1900 and 2200.
+    fn any_date() -> impl Strategy<Value = NaiveDate> {
+        let first = date("1900-01-01");
+        (0u64..110_000).prop_map(move |days| first.checked_add_days(Days::new(days)).unwrap())
+    }
+
+    fn any_policy() -> impl Strategy<Value = LeapDayPolicy> {
+        prop_oneof![Just(LeapDayPolicy::Feb28), Just(LeapDayPolicy::Mar1)]
+    }
+
+    #[test]
+    fn test_next_birthday() {
+        let policy = LeapDayPolicy::Feb28;
+        let dob = date("1990-06-15");
+
+        assert_eq!(
+            next_birthday(&dob, &date("2024-06-14"), policy),
+            date("2024-06-15")
+        );
+        assert_eq!(
+            next_birthday(&dob, &date("2024-06-15"), policy),
+            date("2024-06-15")
+        );
+        assert_eq!(
+            next_birthday(&dob, &date("2024-06-16"), policy),
+            date("2025-06-15")
+        );
+        assert_eq!(days_until_birthday(&dob, &date("2024-06-16"), policy), 364);
+    }
+
+    #[test]
+    fn test_next_birthday_on_leap_day() {
+        let dob = date("2000-02-29");
+
+        let today = date("2023-02-01");
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Feb28),
+            date("2023-02-28")
+        );
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Mar1),
+            date("2023-03-01")
+        );
+
+        let today = date("2024-02-01");
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Feb28),
+            date("2024-02-29")
+        );
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Mar1),
+            date("2024-02-29")
+        );
+
+        // The leap year birthday has passed, next year is a common year.
+        let today = date("2024-03-01");
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Feb28),
+            date("2025-02-28")
+        );
+        assert_eq!(
+            next_birthday(&dob, &today, LeapDayPolicy::Mar1),
+            date("2025-03-01")
+        );
+        assert_eq!(days_until_birthday(&dob, &today, LeapDayPolicy::Mar1), 365);
+    }
+
+    #[test]
+    fn test_age() {
+        let policy = LeapDayPolicy::Feb28;
+        let dob = date("1990-06-15");
+
+        assert_eq!(age(&dob, &date("1990-06-15"), policy), 0);
+        assert_eq!(age(&dob, &date("2024-06-14"), policy), 33);
+        assert_eq!(age(&dob, &date("2024-06-15"), policy), 34);
+
+        let dob = date("2000-02-29");
+        assert_eq!(age(&dob, &date("2023-02-28"), LeapDayPolicy::Feb28), 23);
+        assert_eq!(age(&dob, &date("2023-02-28"), LeapDayPolicy::Mar1), 22);
+        assert_eq!(age(&dob, &date("2023-03-01"), LeapDayPolicy::Mar1), 23);
+    }
+
+    #[test]
+    fn test_days_until_birthday_in_timezone() {
+        // Late evening in UTC is already the next day in Tokyo.
+        let now = "2024-06-14T20:00:00Z".parse().unwrap();
+        let calendar = Calendar::new(FixedClock(now), LeapDayPolicy::Feb28, Tz::UTC);
+        let birthday = |timezone| Birthday {
+            dob: date("1990-06-15"),
+            timezone,
+        };
+
+        assert_eq!(calendar.today(), date("2024-06-14"));
+        assert_eq!(calendar.days_until_birthday(&birthday(None)), 1);
+        assert_eq!(
+            calendar.days_until_birthday(&birthday(Some(Tz::Asia__Tokyo))),
+            0
+        );
+        assert_eq!(
+            calendar.days_until_birthday(&birthday(Some(Tz::America__New_York))),
+            1
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_next_birthday_is_within_a_year(dob in any_date(), today in any_date(), policy in any_policy()) {
+            let birthday = next_birthday(&dob, &today, policy);
+            let days = days_until_birthday(&dob, &today, policy);
+
+            prop_assert!(birthday >= today);
+            prop_assert!((0..=365).contains(&days));
+            prop_assert!(birthday.year() == today.year() || birthday.year() == today.year() + 1);
+        }
+
+        #[test]
+        fn prop_next_birthday_is_on_the_day_of_birth(dob in any_date(), today in any_date(), policy in any_policy()) {
+            let birthday = next_birthday(&dob, &today, policy);
+
+            if dob.with_year(birthday.year()).is_some() {
+                prop_assert_eq!((birthday.month(), birthday.day()), (dob.month(), dob.day()));
+            } else {
+                // Born on the leap day, celebrating in a common year.
+                prop_assert_eq!((dob.month(), dob.day()), (2, 29));
+                let expected = match policy {
+                    LeapDayPolicy::Feb28 => (2, 28),
+                    LeapDayPolicy::Mar1 => (3, 1),
+                };
+                prop_assert_eq!((birthday.month(), birthday.day()), expected);
+            }
+        }
+
+        #[test]
+        fn prop_birthday_today_is_zero_days_away(dob in any_date(), years in 0i32..100, policy in any_policy()) {
+            let today = birthday_in_year(&dob, dob.year() + years, policy);
+
+            prop_assert_eq!(days_until_birthday(&dob, &today, policy), 0);
+        }
+
+        #[test]
+        fn prop_age_increases_on_birthday(dob in any_date(), years in 1i32..100, policy in any_policy()) {
+            let birthday = birthday_in_year(&dob, dob.year() + years, policy);
+            let day_before = birthday.pred_opt().unwrap();
+
+            prop_assert_eq!(age(&dob, &birthday, policy), years as u32);
+            prop_assert_eq!(age(&dob, &day_before, policy), years as u32 - 1);
+        }
+
+        #[test]
+        fn prop_days_until_birthday_decrease_day_by_day(dob in any_date(), today in any_date(), policy in any_policy()) {
+            let days = days_until_birthday(&dob, &today, policy);
+            let tomorrow = today.succ_opt().unwrap();
+
+            if days > 0 {
+                prop_assert_eq!(days_until_birthday(&dob, &tomorrow, policy), days - 1);
+            }
+        }
+    }
+}
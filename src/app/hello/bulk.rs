@@ -0,0 +1,555 @@
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use futures::{stream, Stream, StreamExt};
+
+use super::{
+    api::UserBirthdayRequest,
+    birthday::Calendar,
+    store::{Birthday, ListOrder},
+    validation::{validate_birthday_request, UsernamePolicy},
+};
+use crate::app::{
+    api::{ApiError, ApiResult, FieldViolation, ViolationCode},
+    Store,
+};
+
+/// Maximal size of the imported file.
+pub(crate) const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximal number of the invalid lines listed in the import report.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Number of the records read from the store at once during the export.
+const EXPORT_PAGE_SIZE: usize = 500;
+
+/// Header line of the CSV files.
+const CSV_HEADER: &str = "username,dateOfBirth,timezone";
+
+/// Format of the imported and exported files.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BulkFormat {
+    /// One JSON object per line, e.g. `{"username": "foo", "dateOfBirth": "2000-01-01"}`.
+    #[default]
+    Ndjson,
+    /// `username,dateOfBirth` lines with an optional `timezone` column. The header
+    /// line is optional.
+    Csv,
+}
+
+impl BulkFormat {
+    pub(crate) fn media_type(&self) -> &'static str {
+        match self {
+            BulkFormat::Ndjson => "application/x-ndjson",
+            BulkFormat::Csv => "text/csv",
+        }
+    }
+
+    /// Match the value of the `Content-Type` header, the parameters are ignored.
+    fn from_media_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next()?.trim();
+
+        [BulkFormat::Ndjson, BulkFormat::Csv]
+            .into_iter()
+            .find(|format| format.media_type().eq_ignore_ascii_case(media_type))
+    }
+}
+
+/// Line of the imported NDJSON file.
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ImportLine {
+    username: String,
+    #[serde(flatten)]
+    birthday: UserBirthdayRequest,
+}
+
+/// Line of the exported file.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ExportLine {
+    username: String,
+    date_of_birth: NaiveDate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<Tz>,
+}
+
+/// Invalid line of the imported file.
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub(crate) struct LineError {
+    /// Number of the line, starting from 1.
+    pub line: usize,
+    pub errors: Vec<FieldViolation>,
+}
+
+/// Summary of the import.
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+pub(crate) struct ImportReport {
+    pub imported: usize,
+    pub failed: usize,
+    /// The first invalid lines, see `MAX_REPORTED_ERRORS`.
+    pub errors: Vec<LineError>,
+}
+
+/// Validate the lines of the file and upsert the valid ones. The invalid lines are
+/// skipped and listed in the report, the usernames and the birthdays are validated
+/// the same way as in the API. The blank lines are ignored.
+///
+/// The import stops at the first storage error. The lines imported before it are kept.
+pub(crate) async fn import(
+    store: &Store,
+    calendar: &Calendar,
+    username_policy: &UsernamePolicy,
+    format: BulkFormat,
+    input: &str,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (format == BulkFormat::Csv && index == 0 && is_csv_header(line)) {
+            continue;
+        }
+
+        match parse_line(calendar, username_policy, format, line) {
+            Ok((username, birthday)) => {
+                store.birthdays.upsert_birthday(username, birthday).await?;
+                report.imported += 1;
+            }
+            Err(errors) => {
+                report.failed += 1;
+                if report.errors.len() < MAX_REPORTED_ERRORS {
+                    report.errors.push(LineError {
+                        line: index + 1,
+                        errors,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn is_csv_header(line: &str) -> bool {
+    line.split(',')
+        .next()
+        .is_some_and(|column| column.trim().eq_ignore_ascii_case("username"))
+}
+
+/// Parse and validate a single line of the imported file.
+fn parse_line(
+    calendar: &Calendar,
+    username_policy: &UsernamePolicy,
+    format: BulkFormat,
+    line: &str,
+) -> Result<(String, Birthday), Vec<FieldViolation>> {
+    let ImportLine { username, birthday } = match format {
+        BulkFormat::Ndjson => serde_json::from_str(line).map_err(|err| {
+            vec![FieldViolation::new(
+                "line",
+                ViolationCode::InvalidFormat,
+                &format!("Invalid JSON: {}", err),
+            )]
+        })?,
+        BulkFormat::Csv => parse_csv_line(line)?,
+    };
+
+    let username = username_policy.validate(&username);
+    let birthday = validate_birthday_request(birthday, calendar);
+    let (username, req) = match (username, birthday) {
+        (Ok(username), Ok(req)) => (username, req),
+        // Report all the invalid fields of the line at once.
+        (username, birthday) => {
+            return Err([username.err(), birthday.err()]
+                .into_iter()
+                .flatten()
+                .flat_map(|err| err.errors)
+                .collect())
+        }
+    };
+
+    // The request is validated, the conversions can't fail.
+    let invalid = |err: anyhow::Error| {
+        vec![FieldViolation::new(
+            "line",
+            ViolationCode::InvalidValue,
+            &err.to_string(),
+        )]
+    };
+    let birthday = Birthday {
+        dob: req.dob().map_err(invalid)?,
+        timezone: req.timezone().map_err(invalid)?,
+    };
+
+    Ok((username, birthday))
+}
+
+fn parse_csv_line(line: &str) -> Result<ImportLine, Vec<FieldViolation>> {
+    let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+
+    let (username, date_of_birth, timezone) = match columns.as_slice() {
+        [username, date_of_birth] => (username, date_of_birth, None),
+        [username, date_of_birth, ""] => (username, date_of_birth, None),
+        [username, date_of_birth, timezone] => (username, date_of_birth, Some(timezone)),
+        _ => {
+            return Err(vec![FieldViolation::new(
+                "line",
+                ViolationCode::InvalidFormat,
+                "Invalid CSV line. Expected columns: username,dateOfBirth[,timezone]",
+            )])
+        }
+    };
+
+    Ok(ImportLine {
+        username: username.to_string(),
+        birthday: UserBirthdayRequest {
+            date_of_birth: date_of_birth.to_string(),
+            timezone: timezone.map(ToString::to_string),
+        },
+    })
+}
+
+/// Stream all the birthdays ordered by the username, page by page, so the export
+/// doesn't hold all the records in memory.
+pub(crate) fn export(store: Store, format: BulkFormat) -> impl Stream<Item = Result<Bytes>> {
+    let header = match format {
+        BulkFormat::Ndjson => None,
+        BulkFormat::Csv => Some(Ok(Bytes::from(format!("{}\n", CSV_HEADER)))),
+    };
+
+    let pages = stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+        let store = store.clone();
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+
+            let order = ListOrder::Username { after };
+            let page = store
+                .birthdays
+                .list_birthdays(&order, EXPORT_PAGE_SIZE)
+                .await?;
+            if page.is_empty() {
+                return Ok(None);
+            }
+
+            // The last page is shorter than the limit.
+            let next = (page.len() == EXPORT_PAGE_SIZE)
+                .then(|| page.last().map(|last| last.username.clone()));
+
+            let mut chunk = String::new();
+            for record in page {
+                let line = ExportLine {
+                    username: record.username,
+                    date_of_birth: record.birthday.dob,
+                    timezone: record.birthday.timezone,
+                };
+                chunk.push_str(&format_line(format, &line)?);
+                chunk.push('\n');
+            }
+
+            Ok(Some((Bytes::from(chunk), next)))
+        }
+    });
+
+    stream::iter(header).chain(pages)
+}
+
+fn format_line(format: BulkFormat, line: &ExportLine) -> Result<String> {
+    Ok(match format {
+        BulkFormat::Ndjson => serde_json::to_string(line)?,
+        // The usernames and the time zones can't contain commas.
+        BulkFormat::Csv => format!(
+            "{},{},{}",
+            line.username,
+            line.date_of_birth,
+            line.timezone.map(|tz| tz.name()).unwrap_or_default()
+        ),
+    })
+}
+
+/// Operations of the `/admin/:operation` route along with their methods.
+const OPERATIONS: &[(&str, Method)] = &[
+    ("birthdays:import", Method::POST),
+    ("birthdays:export", Method::GET),
+];
+
+/// Tower middleware routing the operations, the custom methods of the `birthdays`
+/// collection, e.g. `birthdays:import`. The router can't match a parameter in the
+/// middle of the segment, so the whole segment is matched here.
+///
+/// The unknown operations are rejected with `404 Not Found`, and the operations
+/// called with another method with `405 Method Not Allowed`, before the body is read.
+pub(crate) async fn route_operation(
+    Path(operation): Path<String>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((_, method)) = OPERATIONS.iter().find(|(name, _)| *name == operation) else {
+        return ApiError::from_status(
+            StatusCode::NOT_FOUND,
+            &format!("No route for '/admin/{}'", operation),
+        )
+        .into_response();
+    };
+
+    let allowed =
+        request.method() == method || (method == Method::GET && request.method() == Method::HEAD);
+    if !allowed {
+        let error = ApiError::from_status(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &format!("Use {} for '/admin/{}'", method, operation),
+        );
+        let allow = HeaderValue::from_static(method.as_str());
+        return ([(header::ALLOW, allow)], error).into_response();
+    }
+
+    next.run(request).await
+}
+
+/// API handler for importing the birthdays from the NDJSON or CSV file, selected
+/// with the `Content-Type` header.
+pub(crate) async fn import_birthdays(
+    State(store): State<Store>,
+    State(calendar): State<Calendar>,
+    State(username_policy): State<UsernamePolicy>,
+    headers: HeaderMap,
+    body: String,
+) -> ApiResult<Json<ImportReport>> {
+    let format = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(BulkFormat::from_media_type)
+        .ok_or_else(|| {
+            ApiError::from_status(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported file format. Supported content types: application/x-ndjson, text/csv",
+            )
+        })?;
+
+    let report = import(&store, &calendar, &username_policy, format, &body).await?;
+    log::info!(
+        "Imported {} birthdays, {} lines failed",
+        report.imported,
+        report.failed
+    );
+
+    Ok(Json(report))
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub(crate) struct ExportQuery {
+    format: Option<BulkFormat>,
+}
+
+/// API handler for exporting all the birthdays in the NDJSON or CSV format, selected
+/// with the `format` query parameter. The response is streamed.
+pub(crate) async fn export_birthdays(
+    State(store): State<Store>,
+    Query(query): Query<ExportQuery>,
+) -> ApiResult<Response> {
+    let format = query.format.unwrap_or_default();
+    let body = Body::from_stream(export(store, format));
+
+    Ok(([(header::CONTENT_TYPE, format.media_type())], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::post, Router};
+    use futures::TryStreamExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::app::{
+        clock::FixedClock, hello::birthday::LeapDayPolicy, hello::store::InMemoryBirthdayStore,
+    };
+
+    fn calendar() -> Calendar {
+        Calendar::new(
+            FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap()),
+            LeapDayPolicy::Feb28,
+            Tz::UTC,
+        )
+    }
+
+    async fn import_lines(store: &Store, format: BulkFormat, input: &str) -> ImportReport {
+        import(
+            store,
+            &calendar(),
+            &UsernamePolicy::default(),
+            format,
+            input,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn export_lines(store: &Store, format: BulkFormat) -> String {
+        let chunks: Vec<Bytes> = export(store.clone(), format).try_collect().await.unwrap();
+        chunks
+            .iter()
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_format_from_media_type() {
+        assert_eq!(
+            BulkFormat::from_media_type("text/csv; charset=utf-8"),
+            Some(BulkFormat::Csv)
+        );
+        assert_eq!(
+            BulkFormat::from_media_type("application/x-ndjson"),
+            Some(BulkFormat::Ndjson)
+        );
+        assert_eq!(BulkFormat::from_media_type("application/json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_import_ndjson() {
+        let store = Store::new(InMemoryBirthdayStore::default());
+        let input = r#"
+{"username": "foo", "dateOfBirth": "2000-01-01"}
+{"username": "bar", "dateOfBirth": "1990-06-20", "timezone": "Asia/Tokyo"}
+{"username": "foo1", "dateOfBirth": "2030-01-01"}
+not json
+"#;
+
+        let report = import_lines(&store, BulkFormat::Ndjson, input).await;
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.failed, 2);
+        let invalid: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| {
+                let fields: Vec<_> = error
+                    .errors
+                    .iter()
+                    .map(|v| (v.field.as_str(), v.code))
+                    .collect();
+                (error.line, fields)
+            })
+            .collect();
+        assert_eq!(
+            invalid,
+            vec![
+                (
+                    4,
+                    vec![
+                        ("username", ViolationCode::InvalidCharacters),
+                        ("dateOfBirth", ViolationCode::NotInPast),
+                    ]
+                ),
+                (5, vec![("line", ViolationCode::InvalidFormat)]),
+            ]
+        );
+
+        let bar = store.birthdays.get_birthday("bar").await.unwrap().unwrap();
+        assert_eq!(bar.birthday.timezone, Some(Tz::Asia__Tokyo));
+    }
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let store = Store::new(InMemoryBirthdayStore::default());
+        let input = "username,dateOfBirth\nfoo,2000-01-01\nbar,1990-06-20,Asia/Tokyo\nbaz\n";
+
+        let report = import_lines(&store, BulkFormat::Csv, input).await;
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.errors[0].line, 4);
+        assert!(store.birthdays.get_birthday("foo").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_export_roundtrip() {
+        let store = Store::new(InMemoryBirthdayStore::default());
+        let input = "bar,1990-06-20,Asia/Tokyo\nfoo,2000-01-01\n";
+        import_lines(&store, BulkFormat::Csv, input).await;
+
+        assert_eq!(
+            export_lines(&store, BulkFormat::Csv).await,
+            format!(
+                "{}\n{}",
+                CSV_HEADER, "bar,1990-06-20,Asia/Tokyo\nfoo,2000-01-01,\n"
+            )
+        );
+
+        let ndjson = export_lines(&store, BulkFormat::Ndjson).await;
+        assert_eq!(
+            ndjson,
+            "{\"username\":\"bar\",\"dateOfBirth\":\"1990-06-20\",\"timezone\":\"Asia/Tokyo\"}\n\
+             {\"username\":\"foo\",\"dateOfBirth\":\"2000-01-01\"}\n"
+        );
+
+        let copy = Store::new(InMemoryBirthdayStore::default());
+        let report = import_lines(&copy, BulkFormat::Ndjson, &ndjson).await;
+        assert_eq!(report.imported, 2);
+        assert_eq!(export_lines(&copy, BulkFormat::Ndjson).await, ndjson);
+    }
+
+    #[tokio::test]
+    async fn test_export_many_pages() {
+        let store = Store::new(InMemoryBirthdayStore::default());
+        let input: String = (0..EXPORT_PAGE_SIZE * 2 + 1)
+            .map(|i| {
+                // Usernames made of letters only, e.g. `aaa`, `aab`.
+                let name: String = [i / 676, i / 26 % 26, i % 26]
+                    .iter()
+                    .map(|c| (b'a' + *c as u8) as char)
+                    .collect();
+                format!("{},2000-01-01\n", name)
+            })
+            .collect();
+        import_lines(&store, BulkFormat::Csv, &input).await;
+
+        let csv = export_lines(&store, BulkFormat::Csv).await;
+        assert_eq!(csv.lines().count(), EXPORT_PAGE_SIZE * 2 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_route_operation() {
+        let app = Router::new().route(
+            "/admin/:operation",
+            post(|| async { "import" })
+                .get(|| async { "export" })
+                .layer(middleware::from_fn(route_operation)),
+        );
+        let send = |method: Method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let res = send(Method::POST, "/admin/birthdays:import").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send(Method::GET, "/admin/birthdays:export").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(Method::POST, "/admin/birthdays:foo").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = send(Method::POST, "/admin/birthdays:export").await.unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "GET");
+
+        let res = send(Method::GET, "/admin/birthdays:import").await.unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "POST");
+    }
+}
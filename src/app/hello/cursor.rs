@@ -0,0 +1,93 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use super::store::{day_of_year, ListOrder, UserBirthday};
+use crate::app::api::{ApiError, ViolationCode};
+
+/// Position in the listing of the users, passed to the clients as an opaque string.
+/// The cursor remembers the sort order, so the following pages are listed the same way.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "sort", rename_all = "camelCase")]
+pub(crate) enum Cursor {
+    Username {
+        after: String,
+    },
+    /// The `from` day is stored as well, so the listing doesn't shift when the day
+    /// changes between the requests.
+    Upcoming {
+        from: String,
+        day: String,
+        username: String,
+    },
+}
+
+impl Cursor {
+    /// Create the cursor pointing to the last record of the page listed in the given order.
+    pub(crate) fn after(order: &ListOrder, last: &UserBirthday) -> Self {
+        match order {
+            ListOrder::Username { .. } => Cursor::Username {
+                after: last.username.clone(),
+            },
+            ListOrder::Upcoming { from, .. } => Cursor::Upcoming {
+                from: from.clone(),
+                day: day_of_year(&last.birthday.dob),
+                username: last.username.clone(),
+            },
+        }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Failed to serialize the cursor");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub(crate) fn decode(cursor: &str) -> Result<Self, ApiError> {
+        let invalid = |err: &dyn std::fmt::Display| {
+            log::debug!("Invalid cursor '{}': {}", cursor, err);
+            ApiError::invalid_field("cursor", ViolationCode::InvalidValue, "Invalid cursor.")
+        };
+
+        let json = URL_SAFE_NO_PAD.decode(cursor).map_err(|e| invalid(&e))?;
+        serde_json::from_slice(&json).map_err(|e| invalid(&e))
+    }
+
+    /// The order of the listing continuing after the cursor.
+    pub(crate) fn into_order(self) -> ListOrder {
+        match self {
+            Cursor::Username { after } => ListOrder::Username { after: Some(after) },
+            Cursor::Upcoming {
+                from,
+                day,
+                username,
+            } => ListOrder::Upcoming {
+                from,
+                after: Some((day, username)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = Cursor::Upcoming {
+            from: "06-15".to_owned(),
+            day: "12-31".to_owned(),
+            username: "foo".to_owned(),
+        };
+
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+
+        assert_eq!(decoded, cursor);
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        let res = Cursor::decode("foo");
+
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err().message, "Invalid cursor.");
+    }
+}
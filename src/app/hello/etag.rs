@@ -0,0 +1,268 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use super::store::ExpectedRevision;
+
+/// Entity tag of the representation of the record, e.g. `"3"`. The representations
+/// which also depend on the request or on the current date name their variant after
+/// the revision, e.g. `"3-2024-06-15-pl-message"`, so each of them has its own tag.
+///
+/// The revision comes first, so the writes can be conditional on any representation
+/// of the revision, see `IfMatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EntityTag {
+    pub revision: u64,
+    pub variant: Option<String>,
+}
+
+impl EntityTag {
+    /// Tag of the representation of the whole revision, e.g. the response of a write.
+    pub(crate) fn revision(revision: u64) -> Self {
+        EntityTag {
+            revision,
+            variant: None,
+        }
+    }
+
+    /// Tag of one of the variants of the revision.
+    pub(crate) fn variant(revision: u64, variant: String) -> Self {
+        EntityTag {
+            revision,
+            variant: Some(variant),
+        }
+    }
+
+    /// Parse the opaque tag, without the quotes. The tags not made by `EntityTag`
+    /// are ignored.
+    fn parse(tag: &str) -> Option<Self> {
+        let (revision, variant) = match tag.split_once('-') {
+            Some((revision, variant)) => (revision, Some(variant.to_owned())),
+            None => (tag, None),
+        };
+
+        Some(EntityTag {
+            revision: revision.parse().ok()?,
+            variant,
+        })
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        let tag = match &self.variant {
+            Some(variant) => format!("\"{}-{}\"", self.revision, variant),
+            None => format!("\"{}\"", self.revision),
+        };
+
+        HeaderValue::from_str(&tag).expect("The entity tag is a valid header")
+    }
+}
+
+/// Entity tags listed in the conditional header.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExpectedTags {
+    /// The `*` wildcard, matching any representation of the existing record.
+    Any,
+    OneOf(Vec<EntityTag>),
+}
+
+/// Parse the entity tags of the conditional header. The tags which are not made by
+/// `EntityTag` never match. The weak tags are only matched when `weak` is set, see
+/// the weak comparison of RFC 9110, section 8.8.3.2.
+///
+/// Returns `None` if the header is missing.
+fn parse_tags(headers: &HeaderMap, name: &HeaderName, weak: bool) -> Option<ExpectedTags> {
+    let mut values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .peekable();
+    values.peek()?;
+
+    let mut tags = vec![];
+    for tag in values {
+        if tag == "*" {
+            return Some(ExpectedTags::Any);
+        }
+
+        let tag = match tag.strip_prefix("W/") {
+            Some(_) if !weak => continue,
+            Some(tag) => tag,
+            None => tag,
+        };
+        let tag = tag
+            .strip_prefix('"')
+            .and_then(|tag| tag.strip_suffix('"'))
+            .and_then(EntityTag::parse);
+        tags.extend(tag);
+    }
+
+    Some(ExpectedTags::OneOf(tags))
+}
+
+/// Revisions listed in the `If-Match` header. The write must be rejected with
+/// `412 Precondition Failed` if the record doesn't have one of them.
+///
+/// The record is written as a whole, so the tag of any of the variants of the
+/// revision matches it.
+pub(crate) struct IfMatch(pub Option<ExpectedRevision>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let expected =
+            parse_tags(&parts.headers, &header::IF_MATCH, false).map(|tags| match tags {
+                ExpectedTags::Any => ExpectedRevision::Any,
+                ExpectedTags::OneOf(tags) => {
+                    ExpectedRevision::OneOf(tags.into_iter().map(|tag| tag.revision).collect())
+                }
+            });
+
+        Ok(IfMatch(expected))
+    }
+}
+
+/// Entity tags listed in the `If-None-Match` header. The client already has these
+/// representations.
+pub(crate) struct IfNoneMatch(pub Option<ExpectedTags>);
+
+impl IfNoneMatch {
+    /// Check if the client already has the representation with the tag.
+    pub(crate) fn matches(&self, tag: &EntityTag) -> bool {
+        match &self.0 {
+            None => false,
+            Some(ExpectedTags::Any) => true,
+            Some(ExpectedTags::OneOf(tags)) => tags.contains(tag),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfNoneMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(IfNoneMatch(parse_tags(
+            &parts.headers,
+            &header::IF_NONE_MATCH,
+            true,
+        )))
+    }
+}
+
+/// Response tagged with the revision of the record it represents.
+pub(crate) struct Tagged<T>(pub u64, pub T);
+
+impl<T: IntoResponse> IntoResponse for Tagged<T> {
+    fn into_response(self) -> Response {
+        let Tagged(revision, body) = self;
+        let tag = EntityTag::revision(revision).header_value();
+        ([(header::ETAG, tag)], body).into_response()
+    }
+}
+
+/// Response to a conditional `GET`.
+pub(crate) enum Conditional<T> {
+    /// The representation of the record.
+    Modified(EntityTag, T),
+    /// The client already has the representation with the tag.
+    NotModified(EntityTag),
+}
+
+impl<T: IntoResponse> IntoResponse for Conditional<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Conditional::Modified(tag, body) => {
+                ([(header::ETAG, tag.header_value())], body).into_response()
+            }
+            Conditional::NotModified(tag) => (
+                StatusCode::NOT_MODIFIED,
+                [(header::ETAG, tag.header_value())],
+            )
+                .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(values: &[&str], weak: bool) -> Option<ExpectedTags> {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::IF_MATCH, value.parse().unwrap());
+        }
+
+        parse_tags(&headers, &header::IF_MATCH, weak)
+    }
+
+    fn revisions(revisions: &[u64]) -> Option<ExpectedTags> {
+        let tags = revisions.iter().copied().map(EntityTag::revision).collect();
+        Some(ExpectedTags::OneOf(tags))
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(tags(&[], false), None);
+        assert_eq!(tags(&["*"], false), Some(ExpectedTags::Any));
+        assert_eq!(
+            tags(&["\"1\", \"2\"", "\"3\""], false),
+            revisions(&[1, 2, 3])
+        );
+        assert_eq!(
+            tags(&["W/\"1\", \"2\", \"foo\", 3"], false),
+            revisions(&[2])
+        );
+        assert_eq!(tags(&["W/\"1\", \"2\""], true), revisions(&[1, 2]));
+        assert_eq!(
+            tags(&["\"3-2024-06-15-pl-message\""], false),
+            Some(ExpectedTags::OneOf(vec![EntityTag::variant(
+                3,
+                "2024-06-15-pl-message".to_owned()
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_variants_match() {
+        let tag = EntityTag::variant(3, "2024-06-15-pl-message".to_owned());
+        let if_none_match = IfNoneMatch(Some(ExpectedTags::OneOf(vec![tag.clone()])));
+
+        assert!(if_none_match.matches(&tag));
+        assert!(!if_none_match.matches(&EntityTag::revision(3)));
+        assert!(!if_none_match.matches(&EntityTag::variant(3, "2024-06-16-pl-message".to_owned())));
+        assert!(IfNoneMatch(Some(ExpectedTags::Any)).matches(&tag));
+        assert!(!IfNoneMatch(None).matches(&tag));
+    }
+
+    #[test]
+    fn test_conditional_response() {
+        let res = Tagged(3, "foo").into_response();
+        assert_eq!(res.headers()[header::ETAG], "\"3\"");
+
+        let tag = EntityTag::variant(3, "2024-06-15-en-message".to_owned());
+        let res = Conditional::Modified(tag.clone(), "foo").into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ETAG], "\"3-2024-06-15-en-message\"");
+
+        let res = Conditional::<()>::NotModified(tag).into_response();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], "\"3-2024-06-15-en-message\"");
+    }
+}
@@ -0,0 +1,167 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+
+use crate::app::api::{ApiError, ViolationCode};
+
+/// Media type requesting the detailed birthday response through the `Accept` header.
+pub(crate) const DETAILED_MEDIA_TYPE: &str = "application/vnd.revolut.birthday.detailed+json";
+
+/// Format of the birthday response requested by the client.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ResponseFormat {
+    /// Only the greeting message. Kept as the default for the existing clients.
+    #[default]
+    Message,
+    /// The greeting message along with the machine-readable fields.
+    Detailed,
+}
+
+impl ResponseFormat {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ResponseFormat::Message => "message",
+            ResponseFormat::Detailed => "detailed",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct FormatQuery {
+    format: Option<ResponseFormat>,
+}
+
+/// Negotiate the format with the `format` query parameter, falling back to the
+/// `Accept` header. The query parameter wins, as it's easier to set in a browser.
+#[async_trait]
+impl<S> FromRequestParts<S> for ResponseFormat
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<FormatQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| {
+                log::debug!("Invalid format query: {}", err);
+                ApiError::invalid_field(
+                    "format",
+                    ViolationCode::InvalidValue,
+                    "Invalid format. Valid formats: message, detailed",
+                )
+                .into_response()
+            })?;
+
+        if let Some(format) = query.format {
+            return Ok(format);
+        }
+
+        if accepts(&parts.headers, DETAILED_MEDIA_TYPE) {
+            Ok(ResponseFormat::Detailed)
+        } else {
+            Ok(ResponseFormat::Message)
+        }
+    }
+}
+
+/// Response in the negotiated format. The body of the detailed format is labeled with
+/// its media type, and `Vary: Accept` keeps the caches from serving one format for
+/// the other.
+pub(crate) struct Formatted<T>(pub ResponseFormat, pub T);
+
+impl<T: IntoResponse> IntoResponse for Formatted<T> {
+    fn into_response(self) -> Response {
+        let Formatted(format, body) = self;
+        let mut res = body.into_response();
+        let headers = res.headers_mut();
+        // `304 Not Modified` has no body to label.
+        if format == ResponseFormat::Detailed && headers.contains_key(header::CONTENT_TYPE) {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(DETAILED_MEDIA_TYPE),
+            );
+        }
+        headers.append(header::VARY, HeaderValue::from_static("accept"));
+        res
+    }
+}
+
+/// Check if any of the `Accept` headers lists the media type. The parameters of
+/// the media types, e.g. `q`, are ignored.
+fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| item.split(';').next())
+        .any(|item| item.trim().eq_ignore_ascii_case(media_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, StatusCode};
+
+    use super::*;
+
+    async fn format(uri: &str, accept: Option<&str>) -> Result<ResponseFormat, Response> {
+        let mut request = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+
+        ResponseFormat::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_response_format() {
+        let detailed = "application/json;q=0.5, application/vnd.revolut.birthday.detailed+json";
+
+        assert_eq!(
+            format("/hello/foo", None).await.unwrap(),
+            ResponseFormat::Message
+        );
+        assert_eq!(
+            format("/hello/foo", Some("application/json"))
+                .await
+                .unwrap(),
+            ResponseFormat::Message
+        );
+        assert_eq!(
+            format("/hello/foo", Some(detailed)).await.unwrap(),
+            ResponseFormat::Detailed
+        );
+        assert_eq!(
+            format("/hello/foo?format=detailed", None).await.unwrap(),
+            ResponseFormat::Detailed
+        );
+        assert_eq!(
+            format("/hello/foo?format=message", Some(detailed))
+                .await
+                .unwrap(),
+            ResponseFormat::Message
+        );
+        assert!(format("/hello/foo?format=xml", None).await.is_err());
+    }
+
+    #[test]
+    fn test_formatted_response() {
+        let res = Formatted(ResponseFormat::Detailed, axum::Json("foo")).into_response();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], DETAILED_MEDIA_TYPE);
+        assert_eq!(res.headers()[header::VARY], "accept");
+
+        let res = Formatted(ResponseFormat::Message, axum::Json("foo")).into_response();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(res.headers()[header::VARY], "accept");
+
+        let res = Formatted(ResponseFormat::Detailed, StatusCode::NOT_MODIFIED).into_response();
+        assert!(!res.headers().contains_key(header::CONTENT_TYPE));
+        assert_eq!(res.headers()[header::VARY], "accept");
+    }
+}
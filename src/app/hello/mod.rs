@@ -1,3 +1,8 @@
 pub(crate) mod api;
-mod store;
+pub(crate) mod birthday;
+pub(crate) mod bulk;
+mod cursor;
+pub(crate) mod etag;
+pub(crate) mod format;
+pub(crate) mod store;
 pub mod validation;
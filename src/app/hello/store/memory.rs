@@ -0,0 +1,232 @@
+use std::{collections::HashMap, path::Path, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+
+use super::{
+    day_of_year, Birthday, BirthdayStore, ExpectedRevision, ListOrder, StoredBirthday, UserBirthday,
+};
+use crate::app::{
+    audit::AuditEntry,
+    backup::{BackupStore, BackupsNotSupported},
+    idempotency::{IdempotencyRecord, IdempotencyStore, StoredResponse},
+    migrations::{Migration, SchemaStore},
+};
+
+/// `BirthdayStore` that keeps all the records in the process memory.
+/// The data is lost when the application stops, so it should only be used for
+/// testing and local development.
+#[derive(Default)]
+pub(crate) struct InMemoryBirthdayStore {
+    records: RwLock<HashMap<String, StoredBirthday>>,
+    audit: RwLock<Vec<AuditEntry>>,
+    idempotency: RwLock<HashMap<String, IdempotencyRecord>>,
+}
+
+impl InMemoryBirthdayStore {
+    #[cfg(test)]
+    pub(crate) fn audit_entries(&self) -> Vec<AuditEntry> {
+        self.audit.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl BirthdayStore for InMemoryBirthdayStore {
+    async fn get_birthday(&self, username: &str) -> Result<Option<StoredBirthday>> {
+        let records = self
+            .records
+            .read()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+
+        Ok(records.get(username).cloned())
+    }
+
+    async fn upsert_birthday(&self, username: String, birthday: Birthday) -> Result<u64> {
+        let mut records = self
+            .records
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        let revision = records.get(&username).map_or(0, |r| r.revision) + 1;
+        records.insert(username, StoredBirthday { birthday, revision });
+
+        Ok(revision)
+    }
+
+    async fn update_birthday(
+        &self,
+        username: &str,
+        birthday: Birthday,
+        expected: &ExpectedRevision,
+    ) -> Result<Option<u64>> {
+        let mut records = self
+            .records
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+
+        match records.get_mut(username) {
+            Some(record) if expected.matches(record.revision) => {
+                record.birthday = birthday;
+                record.revision += 1;
+                Ok(Some(record.revision))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_birthday(&self, username: &str, audit: AuditEntry) -> Result<bool> {
+        let mut records = self
+            .records
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        let mut entries = self
+            .audit
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+
+        if records.remove(username).is_none() {
+            return Ok(false);
+        }
+        entries.push(audit);
+
+        Ok(true)
+    }
+
+    async fn list_birthdays(&self, order: &ListOrder, limit: usize) -> Result<Vec<UserBirthday>> {
+        let records = self
+            .records
+            .read()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+
+        let mut page: Vec<UserBirthday> = records
+            .iter()
+            .map(|(username, record)| UserBirthday {
+                username: username.clone(),
+                birthday: record.birthday.clone(),
+            })
+            .collect();
+
+        match order {
+            ListOrder::Username { after } => {
+                if let Some(after) = after {
+                    page.retain(|b| &b.username > after);
+                }
+                page.sort_by(|a, b| a.username.cmp(&b.username));
+            }
+            ListOrder::Upcoming { from, after } => {
+                // Days before `from` go to the end of the list, as they are next year.
+                let key = |b: &UserBirthday| {
+                    let day = day_of_year(&b.birthday.dob);
+                    (day < *from, day, b.username.clone())
+                };
+
+                if let Some((day, username)) = after {
+                    let after = (day < from, day.clone(), username.clone());
+                    page.retain(|b| key(b) > after);
+                }
+                page.sort_by_key(key);
+            }
+        }
+        page.truncate(limit);
+
+        Ok(page)
+    }
+
+    async fn count_birthdays(&self) -> Result<u64> {
+        let records = self
+            .records
+            .read()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+
+        Ok(records.len() as u64)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for InMemoryBirthdayStore {
+    async fn claim_key(
+        &self,
+        key: &str,
+        record: IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let mut records = self
+            .idempotency
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        records.retain(|_, record| record.expires_at > now);
+
+        if let Some(existing) = records.get(key) {
+            return Ok(Some(existing.clone()));
+        }
+        records.insert(key.to_owned(), record);
+
+        Ok(None)
+    }
+
+    async fn complete_key(&self, key: &str, response: StoredResponse) -> Result<()> {
+        let mut records = self
+            .idempotency
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        if let Some(record) = records.get_mut(key) {
+            record.response = Some(response);
+        }
+
+        Ok(())
+    }
+
+    async fn release_key(&self, key: &str) -> Result<()> {
+        let mut records = self
+            .idempotency
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        records.remove(key);
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64> {
+        let mut records = self
+            .idempotency
+            .write()
+            .map_err(|_| anyhow!("In-memory store lock is poisoned"))?;
+        let before = records.len();
+        records.retain(|_, record| record.expires_at > now);
+
+        Ok((before - records.len()) as u64)
+    }
+}
+
+/// The data of the in-memory store is lost anyway, there is nothing worth backing up.
+#[async_trait]
+impl BackupStore for InMemoryBirthdayStore {
+    async fn backup(&self) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        Err(BackupsNotSupported("The in-memory storage doesn't support the backups").into())
+    }
+
+    async fn restore(&self, _file: &Path) -> Result<()> {
+        Err(BackupsNotSupported("The in-memory storage doesn't support the backups").into())
+    }
+}
+
+/// The in-memory store has no schema, so there is nothing to migrate.
+#[async_trait]
+impl SchemaStore for InMemoryBirthdayStore {
+    fn migrations(&self) -> &'static [Migration] {
+        &[]
+    }
+
+    async fn schema_version(&self) -> Result<u32> {
+        Ok(0)
+    }
+
+    async fn apply_migration(&self, _migration: &Migration) -> Result<bool> {
+        Ok(true)
+    }
+}
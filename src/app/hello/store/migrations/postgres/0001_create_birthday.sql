@@ -0,0 +1,10 @@
+-- The table could have been created by the application before the migrations
+-- were introduced.
+CREATE TABLE IF NOT EXISTS birthday (
+    username TEXT PRIMARY KEY,
+    dob DATE NOT NULL
+);
+
+ALTER TABLE birthday
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,10 @@
+-- Audit trail. It must not contain any personal data other than the username.
+CREATE TABLE audit (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    username TEXT NOT NULL,
+    request_id TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_username ON audit (username);
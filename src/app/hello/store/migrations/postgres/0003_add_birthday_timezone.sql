@@ -0,0 +1,3 @@
+-- IANA name of the user's time zone, e.g. `Europe/London`.
+-- The server's default time zone is used when it's not set.
+ALTER TABLE birthday ADD COLUMN IF NOT EXISTS timezone TEXT;
@@ -0,0 +1,3 @@
+-- Revision of the record, incremented on every write, so the clients can detect
+-- the concurrent modifications.
+ALTER TABLE birthday ADD COLUMN IF NOT EXISTS revision BIGINT NOT NULL DEFAULT 1;
@@ -0,0 +1,11 @@
+-- Responses of the write requests, replayed when the requests are retried with the
+-- same `Idempotency-Key`. The response is stored as JSON, it's NULL while the request
+-- is being handled.
+CREATE TABLE idempotency (
+    key TEXT PRIMARY KEY,
+    fingerprint TEXT NOT NULL,
+    response TEXT,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idempotency_expires_at ON idempotency (expires_at);
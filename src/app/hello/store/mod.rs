@@ -0,0 +1,688 @@
+use anyhow::Result;
+use axum::async_trait;
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+
+use crate::app::audit::AuditEntry;
+
+mod memory;
+mod postgres;
+mod surreal;
+
+pub(crate) use memory::InMemoryBirthdayStore;
+pub(crate) use postgres::PostgresBirthdayStore;
+pub(crate) use surreal::{is_connection_error, SurrealBirthdayStore};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Birthday {
+    pub dob: NaiveDate,
+    /// Time zone in which the user celebrates the birthday. The server's default
+    /// time zone is used if it's not set.
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+}
+
+#[cfg(test)]
+impl Birthday {
+    /// Birthday celebrated in the server's default time zone.
+    pub(crate) fn new(dob: NaiveDate) -> Self {
+        Birthday {
+            dob,
+            timezone: None,
+        }
+    }
+}
+
+/// Birthday along with the revision of the record.
+/// The revision starts at 1 and is incremented on every write, so the clients can
+/// detect the concurrent modifications, see `BirthdayStore::update_birthday`.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StoredBirthday {
+    #[serde(flatten)]
+    pub birthday: Birthday,
+    /// The records written before the revisions were introduced have no revision
+    /// until the migrations are applied.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// Revision the record must have for the conditional update to be applied.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExpectedRevision {
+    /// The record must exist, with any revision.
+    Any,
+    /// The record must have one of the revisions.
+    OneOf(Vec<u64>),
+}
+
+impl ExpectedRevision {
+    pub(crate) fn matches(&self, revision: u64) -> bool {
+        match self {
+            ExpectedRevision::Any => true,
+            ExpectedRevision::OneOf(revisions) => revisions.contains(&revision),
+        }
+    }
+}
+
+/// Birthday of the user returned when listing the records.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct UserBirthday {
+    pub username: String,
+    #[serde(flatten)]
+    pub birthday: Birthday,
+}
+
+/// Order of the listed birthdays.
+/// The listing starts right after the record at the `after` position, which allows
+/// paginating through the records with a cursor.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ListOrder {
+    /// Ordered by the username. The position is the username.
+    Username { after: Option<String> },
+    /// Ordered by the day of the year of the birthday, formatted as `MM-DD`, starting
+    /// from the `from` day and wrapping around the end of the year. The users with
+    /// the birthday on the same day are ordered by the username. The position is the
+    /// pair of the day and the username.
+    Upcoming {
+        from: String,
+        after: Option<(String, String)>,
+    },
+}
+
+/// Format the day of the year of the date as `MM-DD`, the key used by `ListOrder::Upcoming`.
+pub(crate) fn day_of_year(date: &NaiveDate) -> String {
+    date.format("%m-%d").to_string()
+}
+
+/// Storage abstraction for the users' birthdays.
+///
+/// The trait is object safe, so the backend can be selected at runtime and shared
+/// between the request handlers as `Arc<dyn BirthdayStore>`.
+#[async_trait]
+pub(crate) trait BirthdayStore: Send + Sync {
+    async fn get_birthday(&self, username: &str) -> Result<Option<StoredBirthday>>;
+    /// Create or replace the user's record. Returns the new revision of the record.
+    async fn upsert_birthday(&self, username: String, birthday: Birthday) -> Result<u64>;
+    /// Replace the existing record if it has the expected revision. The check and
+    /// the write are atomic. Returns the new revision of the record, or `None` if
+    /// the record doesn't exist or has a different revision.
+    async fn update_birthday(
+        &self,
+        username: &str,
+        birthday: Birthday,
+        expected: &ExpectedRevision,
+    ) -> Result<Option<u64>>;
+    /// Delete the user's record and record the erasure in the audit trail, in one
+    /// transaction. Returns `false` if there was no record to delete, nothing is
+    /// recorded then.
+    async fn delete_birthday(&self, username: &str, audit: AuditEntry) -> Result<bool>;
+    /// List at most `limit` birthdays in the given order.
+    async fn list_birthdays(&self, order: &ListOrder, limit: usize) -> Result<Vec<UserBirthday>>;
+    /// Count all the stored birthdays.
+    async fn count_birthdays(&self) -> Result<u64>;
+    /// Make the cheapest round trip to the database, checking it's reachable.
+    async fn ping(&self) -> Result<()>;
+    /// Release the connections to the database on shutdown. The embedded database is
+    /// closed when its last handle is dropped, its writes are already persisted.
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::{engine::local::Mem, Surreal};
+
+    use super::*;
+    use chrono::{DateTime, Duration, Utc};
+
+    use crate::app::{
+        audit::{AuditAction, AuditEntry},
+        idempotency::{IdempotencyRecord, IdempotencyStore, StoredResponse},
+        migrations::{migrate, SchemaStore},
+    };
+
+    fn erasure(username: &str) -> AuditEntry {
+        AuditEntry {
+            action: AuditAction::Erase,
+            username: username.to_owned(),
+            request_id: Some("42".to_owned()),
+        }
+    }
+
+    async fn birthday(store: &dyn BirthdayStore, username: &str) -> Option<Birthday> {
+        let record = store.get_birthday(username).await.unwrap();
+        record.map(|record| record.birthday)
+    }
+
+    /// Run the same set of assertions against any backend implementation.
+    async fn assert_store_roundtrip(store: &dyn BirthdayStore) {
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+
+        assert_eq!(birthday(store, "foo").await, None);
+
+        store
+            .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+            .await
+            .unwrap();
+        assert_eq!(birthday(store, "foo").await, Some(Birthday::new(dob)));
+
+        let new_birthday = Birthday {
+            dob: NaiveDate::from_ymd_opt(2001, 2, 3).unwrap(),
+            timezone: Some(Tz::Asia__Tokyo),
+        };
+        store
+            .upsert_birthday("foo".to_owned(), new_birthday.clone())
+            .await
+            .unwrap();
+        assert_eq!(birthday(store, "foo").await, Some(new_birthday));
+
+        // The time zone is cleared when it's not set anymore.
+        store
+            .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+            .await
+            .unwrap();
+        assert_eq!(birthday(store, "foo").await, Some(Birthday::new(dob)));
+
+        assert_eq!(birthday(store, "bar").await, None);
+
+        assert!(store.delete_birthday("foo", erasure("foo")).await.unwrap());
+        assert_eq!(birthday(store, "foo").await, None);
+        assert!(!store.delete_birthday("foo", erasure("foo")).await.unwrap());
+    }
+
+    /// Check the revisions and the conditional updates against any backend implementation.
+    async fn assert_store_revisions(store: &dyn BirthdayStore) {
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let new_dob = NaiveDate::from_ymd_opt(2001, 2, 3).unwrap();
+
+        // The missing record is not created by the conditional update.
+        let updated = store
+            .update_birthday("foo", Birthday::new(dob), &ExpectedRevision::Any)
+            .await
+            .unwrap();
+        assert_eq!(updated, None);
+        assert_eq!(birthday(store, "foo").await, None);
+
+        assert_eq!(
+            store
+                .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store.get_birthday("foo").await.unwrap(),
+            Some(StoredBirthday {
+                birthday: Birthday::new(dob),
+                revision: 2,
+            })
+        );
+
+        // The stale revision is rejected.
+        let updated = store
+            .update_birthday(
+                "foo",
+                Birthday::new(new_dob),
+                &ExpectedRevision::OneOf(vec![1]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated, None);
+        assert_eq!(birthday(store, "foo").await, Some(Birthday::new(dob)));
+
+        let updated = store
+            .update_birthday(
+                "foo",
+                Birthday::new(new_dob),
+                &ExpectedRevision::OneOf(vec![1, 2]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated, Some(3));
+        assert_eq!(birthday(store, "foo").await, Some(Birthday::new(new_dob)));
+
+        let updated = store
+            .update_birthday("foo", Birthday::new(dob), &ExpectedRevision::Any)
+            .await
+            .unwrap();
+        assert_eq!(updated, Some(4));
+    }
+
+    /// Check the claiming of the idempotency keys against any backend implementation.
+    async fn assert_idempotency_store(store: &dyn IdempotencyStore) {
+        let now: DateTime<Utc> = "2024-06-15T12:00:00Z".parse().unwrap();
+        let record = |fingerprint: &str| IdempotencyRecord {
+            fingerprint: fingerprint.to_owned(),
+            response: None,
+            expires_at: now + Duration::hours(1),
+        };
+        let later = now + Duration::hours(2);
+        let later_record = |fingerprint: &str| IdempotencyRecord {
+            expires_at: later + Duration::hours(1),
+            ..record(fingerprint)
+        };
+        let response = StoredResponse {
+            status: 204,
+            headers: vec![("etag".to_owned(), "\"1\"".to_owned())],
+            body: String::new(),
+        };
+
+        assert_eq!(
+            store.claim_key("key", record("foo"), now).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            store.claim_key("key", record("bar"), now).await.unwrap(),
+            Some(record("foo"))
+        );
+
+        store.complete_key("key", response.clone()).await.unwrap();
+        assert_eq!(
+            store.claim_key("key", record("foo"), now).await.unwrap(),
+            Some(IdempotencyRecord {
+                response: Some(response),
+                ..record("foo")
+            })
+        );
+
+        // The key can be claimed again when it's released or expired.
+        store.release_key("key").await.unwrap();
+        assert_eq!(
+            store.claim_key("key", record("bar"), now).await.unwrap(),
+            None
+        );
+
+        assert_eq!(
+            store
+                .claim_key("key", later_record("baz"), later)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            store
+                .claim_key("key", later_record("foo"), later)
+                .await
+                .unwrap(),
+            Some(later_record("baz"))
+        );
+
+        let expired = later + Duration::hours(2);
+        assert_eq!(store.purge_expired(expired).await.unwrap(), 1);
+        assert_eq!(store.purge_expired(expired).await.unwrap(), 0);
+    }
+
+    /// Check the listing of the records against any backend implementation.
+    async fn assert_store_listing(store: &dyn BirthdayStore) {
+        for (username, dob) in [
+            ("dave", "1990-06-15"),
+            ("alice", "1985-12-31"),
+            ("carol", "2000-01-01"),
+            ("bob", "1995-06-15"),
+        ] {
+            store
+                .upsert_birthday(username.to_owned(), Birthday::new(dob.parse().unwrap()))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(store.count_birthdays().await.unwrap(), 4);
+
+        let usernames = |page: Vec<UserBirthday>| -> Vec<String> {
+            page.into_iter().map(|b| b.username).collect()
+        };
+
+        let order = ListOrder::Username { after: None };
+        let page = store.list_birthdays(&order, 3).await.unwrap();
+        assert_eq!(usernames(page), vec!["alice", "bob", "carol"]);
+
+        let order = ListOrder::Username {
+            after: Some("carol".to_owned()),
+        };
+        let page = store.list_birthdays(&order, 3).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].username, "dave");
+        assert_eq!(page[0].birthday.dob, "1990-06-15".parse().unwrap());
+
+        let order = ListOrder::Upcoming {
+            from: "06-15".to_owned(),
+            after: None,
+        };
+        let page = store.list_birthdays(&order, 10).await.unwrap();
+        assert_eq!(usernames(page), vec!["bob", "dave", "alice", "carol"]);
+
+        let order = ListOrder::Upcoming {
+            from: "06-15".to_owned(),
+            after: Some(("06-15".to_owned(), "dave".to_owned())),
+        };
+        let page = store.list_birthdays(&order, 1).await.unwrap();
+        assert_eq!(usernames(page), vec!["alice"]);
+
+        let order = ListOrder::Upcoming {
+            from: "06-15".to_owned(),
+            after: Some(("12-31".to_owned(), "alice".to_owned())),
+        };
+        let page = store.list_birthdays(&order, 10).await.unwrap();
+        assert_eq!(usernames(page), vec!["carol"]);
+
+        let order = ListOrder::Upcoming {
+            from: "06-16".to_owned(),
+            after: None,
+        };
+        let page = store.list_birthdays(&order, 10).await.unwrap();
+        assert_eq!(usernames(page), vec!["alice", "carol", "bob", "dave"]);
+    }
+
+    /// Walk the upcoming birthdays page by page against any backend implementation.
+    async fn assert_store_paging(store: &dyn BirthdayStore) {
+        for (username, dob) in [
+            ("erin", "1990-03-01"),
+            ("dave", "1990-06-15"),
+            ("alice", "1985-12-31"),
+            ("carol", "2000-01-01"),
+            ("bob", "1995-06-15"),
+        ] {
+            store
+                .upsert_birthday(username.to_owned(), Birthday::new(dob.parse().unwrap()))
+                .await
+                .unwrap();
+        }
+
+        let mut usernames = vec![];
+        let mut after = None;
+        loop {
+            let order = ListOrder::Upcoming {
+                from: "06-15".to_owned(),
+                after,
+            };
+            let page = store.list_birthdays(&order, 2).await.unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some((
+                last.birthday.dob.format("%m-%d").to_string(),
+                last.username.clone(),
+            ));
+            usernames.extend(page.into_iter().map(|b| b.username));
+        }
+
+        assert_eq!(usernames, vec!["bob", "dave", "alice", "carol", "erin"]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store() {
+        let store = InMemoryBirthdayStore::default();
+        assert_store_roundtrip(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_revisions() {
+        let store = InMemoryBirthdayStore::default();
+        assert_store_revisions(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_idempotency_store() {
+        let store = InMemoryBirthdayStore::default();
+        assert_idempotency_store(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_listing() {
+        let store = InMemoryBirthdayStore::default();
+        assert_store_listing(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_paging() {
+        let store = InMemoryBirthdayStore::default();
+        assert_store_paging(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_surreal_store_paging() {
+        let store = surreal_store().await;
+        migrate(&store).await.unwrap();
+
+        assert_store_paging(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_surreal_store_listing() {
+        let store = surreal_store().await;
+        migrate(&store).await.unwrap();
+
+        assert_store_listing(&store).await;
+    }
+
+    async fn surreal_store() -> SurrealBirthdayStore<surrealdb::engine::local::Db> {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("revolut-test").use_db("revolut").await.unwrap();
+
+        SurrealBirthdayStore::new(db)
+    }
+
+    #[tokio::test]
+    async fn test_surreal_store() {
+        let store = surreal_store().await;
+        migrate(&store).await.unwrap();
+
+        assert_store_roundtrip(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_surreal_store_revisions() {
+        let store = surreal_store().await;
+        migrate(&store).await.unwrap();
+
+        assert_store_revisions(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_surreal_idempotency_store() {
+        let store = surreal_store().await;
+        migrate(&store).await.unwrap();
+
+        assert_idempotency_store(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_surreal_migrations() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("revolut-test").use_db("revolut").await.unwrap();
+        let store = SurrealBirthdayStore::new(db.clone());
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+
+        // Records written before the migrations should be preserved, with the date of
+        // birth stored as a string back then.
+        db.query("CREATE birthday:foo SET dob = '2000-01-01'")
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        assert_eq!(store.schema_version().await.unwrap(), 0);
+
+        migrate(&store).await.unwrap();
+        migrate(&store).await.unwrap();
+
+        let latest = store.migrations().last().unwrap().version;
+        assert_eq!(store.schema_version().await.unwrap(), latest);
+        assert_eq!(birthday(&store, "foo").await, Some(Birthday::new(dob)));
+        // The revision of the existing record is backfilled.
+        let record = store.get_birthday("foo").await.unwrap().unwrap();
+        assert_eq!(record.revision, 1);
+
+        // Only the dates are stored from now on.
+        for dob in ["'2000-01-02'", "d'2000-01-02T10:00:00Z'"] {
+            let res = db
+                .query(format!("CREATE birthday:bar SET dob = {}", dob))
+                .await
+                .unwrap()
+                .check();
+            assert!(res.is_err(), "{}", dob);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_surreal_concurrent_migrations() {
+        let store = surreal_store().await;
+
+        // Like two replicas starting at the same time.
+        let (first, second) = tokio::join!(migrate(&store), migrate(&store));
+        first.unwrap();
+        second.unwrap();
+
+        let latest = store.migrations().last().unwrap().version;
+        assert_eq!(store.schema_version().await.unwrap(), latest);
+    }
+
+    #[tokio::test]
+    async fn test_surreal_audit() {
+        let db = Surreal::new::<Mem>(()).await.unwrap();
+        db.use_ns("revolut-test").use_db("revolut").await.unwrap();
+        let store = SurrealBirthdayStore::new(db.clone());
+        migrate(&store).await.unwrap();
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+
+        store
+            .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+            .await
+            .unwrap();
+        assert!(store.delete_birthday("foo", erasure("foo")).await.unwrap());
+        // Only the actual erasures are recorded.
+        assert!(!store.delete_birthday("foo", erasure("foo")).await.unwrap());
+
+        let mut response = db.query("SELECT VALUE username FROM audit").await.unwrap();
+        let usernames: Vec<String> = response.take(0).unwrap();
+        assert_eq!(usernames, vec!["foo"]);
+    }
+
+    /// PostgreSQL store in the new schema of the `DATABASE_URL` database. The tests
+    /// using it are ignored by default, run them with `cargo test -- --ignored`.
+    async fn postgres_store(schema: &str) -> PostgresBirthdayStore {
+        let url = std::env::var("DATABASE_URL")
+            .expect("The PostgreSQL tests require the DATABASE_URL of the test database");
+
+        PostgresBirthdayStore::connect_to_new_schema(&url, schema)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_store() {
+        let store = postgres_store("test_store").await;
+        migrate(&store).await.unwrap();
+
+        assert_store_roundtrip(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_store_revisions() {
+        let store = postgres_store("test_store_revisions").await;
+        migrate(&store).await.unwrap();
+
+        assert_store_revisions(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_idempotency_store() {
+        let store = postgres_store("test_idempotency_store").await;
+        migrate(&store).await.unwrap();
+
+        assert_idempotency_store(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_store_listing() {
+        let store = postgres_store("test_store_listing").await;
+        migrate(&store).await.unwrap();
+
+        assert_store_listing(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_store_paging() {
+        let store = postgres_store("test_store_paging").await;
+        migrate(&store).await.unwrap();
+
+        assert_store_paging(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_migrations() {
+        let store = postgres_store("test_migrations").await;
+
+        // The table created by the application before the migrations were introduced.
+        store
+            .execute(
+                "CREATE TABLE birthday (username TEXT PRIMARY KEY, dob DATE NOT NULL);
+                 INSERT INTO birthday (username, dob) VALUES ('foo', '2000-01-01');",
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.schema_version().await.unwrap(), 0);
+
+        migrate(&store).await.unwrap();
+        migrate(&store).await.unwrap();
+
+        let latest = store.migrations().last().unwrap().version;
+        assert_eq!(store.schema_version().await.unwrap(), latest);
+        // The existing record is preserved, with the revision backfilled.
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        assert_eq!(
+            store.get_birthday("foo").await.unwrap(),
+            Some(StoredBirthday {
+                birthday: Birthday::new(dob),
+                revision: 1,
+            })
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_concurrent_migrations() {
+        let store = postgres_store("test_concurrent_migrations").await;
+
+        // Like two replicas starting at the same time, each migration is applied once
+        // under the advisory lock.
+        let (first, second) = tokio::join!(migrate(&store), migrate(&store));
+        first.unwrap();
+        second.unwrap();
+
+        let latest = store.migrations().last().unwrap().version;
+        assert_eq!(store.schema_version().await.unwrap(), latest);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the DATABASE_URL of a PostgreSQL database"]
+    async fn test_postgres_audit() {
+        let store = postgres_store("test_audit").await;
+        migrate(&store).await.unwrap();
+        let dob = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+
+        store
+            .upsert_birthday("foo".to_owned(), Birthday::new(dob))
+            .await
+            .unwrap();
+        assert!(store.delete_birthday("foo", erasure("foo")).await.unwrap());
+        // Only the actual erasures are recorded.
+        assert!(!store.delete_birthday("foo", erasure("foo")).await.unwrap());
+
+        assert_eq!(store.audited_usernames().await.unwrap(), vec!["foo"]);
+        assert_eq!(birthday(&store, "foo").await, None);
+    }
+}
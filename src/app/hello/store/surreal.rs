@@ -0,0 +1,459 @@
+use std::{future::IntoFuture, path::Path};
+
+use anyhow::{Context, Result};
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use surrealdb::{sql::Datetime, Connection, Surreal};
+
+use super::{Birthday, BirthdayStore, ExpectedRevision, ListOrder, StoredBirthday, UserBirthday};
+use crate::app::{
+    audit::AuditEntry,
+    backup::{BackupStore, BackupsNotSupported},
+    idempotency::{IdempotencyRecord, IdempotencyStore, StoredResponse},
+    migrations::{Migration, SchemaStore},
+    retry::Backoff,
+};
+
+static BIRTHDAY_NS: &str = "birthday";
+static IDEMPOTENCY_NS: &str = "idempotency";
+
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Define the birthday table",
+        script: include_str!("migrations/surreal/0001_define_birthday.surql"),
+    },
+    Migration {
+        version: 2,
+        description: "Define the audit table",
+        script: include_str!("migrations/surreal/0002_define_audit.surql"),
+    },
+    Migration {
+        version: 3,
+        description: "Define the time zone of the birthday",
+        script: include_str!("migrations/surreal/0003_define_birthday_timezone.surql"),
+    },
+    Migration {
+        version: 4,
+        description: "Define the revision of the birthday",
+        script: include_str!("migrations/surreal/0004_define_birthday_revision.surql"),
+    },
+    Migration {
+        version: 5,
+        description: "Define the idempotency table",
+        script: include_str!("migrations/surreal/0005_define_idempotency.surql"),
+    },
+    Migration {
+        version: 6,
+        description: "Define the date of birth as a datetime",
+        script: include_str!("migrations/surreal/0006_define_birthday_dob_datetime.surql"),
+    },
+];
+
+/// `BirthdayStore` backed by SurrealDB.
+/// The store is generic over the connection, so it works with the embedded engine
+/// as well as with a remote SurrealDB server.
+pub(crate) struct SurrealBirthdayStore<C: Connection> {
+    db: Surreal<C>,
+    backoff: Backoff,
+}
+
+impl<C: Connection> SurrealBirthdayStore<C> {
+    /// Create a store which doesn't retry the failed queries.
+    pub(crate) fn new(db: Surreal<C>) -> Self {
+        SurrealBirthdayStore {
+            db,
+            backoff: Backoff::with_retries(0),
+        }
+    }
+
+    /// Retry the queries failed due to connection errors using the given policy.
+    /// Only the idempotent queries are retried.
+    pub(crate) fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+/// Check if the error was caused by the connection to the remote server.
+pub(crate) fn is_connection_error(err: &surrealdb::Error) -> bool {
+    use surrealdb::error::Api;
+
+    matches!(
+        err,
+        surrealdb::Error::Api(Api::Http(_) | Api::Ws(_) | Api::ConnectionUninitialised)
+    )
+}
+
+#[async_trait]
+impl<C: Connection> BirthdayStore for SurrealBirthdayStore<C> {
+    async fn get_birthday(&self, username: &str) -> Result<Option<StoredBirthday>> {
+        let record: Option<SurrealBirthdayRecord> = self
+            .backoff
+            .retry("Selecting birthday", is_connection_error, || {
+                self.db.select((BIRTHDAY_NS, username)).into_future()
+            })
+            .await?;
+
+        Ok(record.map(Into::into))
+    }
+
+    async fn upsert_birthday(&self, username: String, birthday: Birthday) -> Result<u64> {
+        // Not retried, the update might have been applied before the connection failed
+        // and the retry would bump the revision once more.
+        // Merge, so the audit fields maintained by the database are preserved.
+        let record: Option<SurrealBirthdayRecord> = self
+            .db
+            .update((BIRTHDAY_NS, username.as_str()))
+            .merge(SurrealBirthday::from(birthday))
+            .await?;
+        let record = record.context("The updated birthday was not returned")?;
+
+        Ok(record.revision)
+    }
+
+    async fn update_birthday(
+        &self,
+        username: &str,
+        birthday: Birthday,
+        expected: &ExpectedRevision,
+    ) -> Result<Option<u64>> {
+        let revisions = match expected {
+            ExpectedRevision::Any => None,
+            ExpectedRevision::OneOf(revisions) => Some(revisions.clone()),
+        };
+
+        // Not retried, the update might have been applied before the connection failed.
+        // The `UPDATE` of a missing record would create it, unless it's filtered out.
+        let mut response = self
+            .db
+            .query(
+                "UPDATE type::thing('birthday', $username) MERGE $birthday
+                 WHERE revision != NONE AND ($revisions = NONE OR revision IN $revisions)
+                 RETURN VALUE revision",
+            )
+            .bind(("username", username.to_owned()))
+            .bind(("birthday", SurrealBirthday::from(birthday)))
+            .bind(("revisions", revisions))
+            .await?;
+        let revisions: Vec<u64> = response.take(0)?;
+
+        Ok(revisions.first().copied())
+    }
+
+    async fn delete_birthday(&self, username: &str, audit: AuditEntry) -> Result<bool> {
+        // Not retried, the erasure might have been applied before the connection failed
+        // and the retry would report the user as missing.
+        let mut response = self
+            .db
+            .query("BEGIN TRANSACTION")
+            .query(
+                "LET $existed = array::len(
+                    (SELECT id FROM type::thing('birthday', $username))
+                 ) > 0",
+            )
+            .query("DELETE type::thing('birthday', $username)")
+            .query("IF $existed THEN (CREATE audit CONTENT $audit) END")
+            .query("RETURN $existed")
+            .query("COMMIT TRANSACTION")
+            .bind(("username", username.to_owned()))
+            .bind(("audit", audit))
+            .await?
+            .check()?;
+        let last = response.num_statements() - 1;
+        let deleted: Option<bool> = response.take(last)?;
+
+        Ok(deleted.unwrap_or_default())
+    }
+
+    async fn list_birthdays(&self, order: &ListOrder, limit: usize) -> Result<Vec<UserBirthday>> {
+        let query = match order {
+            ListOrder::Username { after } => self
+                .db
+                .query(
+                    "SELECT meta::id(id) AS username, * FROM birthday
+                     WHERE $after = NONE OR id > type::thing('birthday', $after)
+                     ORDER BY id
+                     LIMIT $limit",
+                )
+                .bind(("after", after.clone())),
+            ListOrder::Upcoming { from, after } => {
+                let (after_day, after_username) = after.clone().unzip();
+                let after_wrapped = after_day.as_ref().map(|day| day < from);
+
+                // Days before `from` go to the end of the list, as they are next year.
+                self.db
+                    .query(
+                        "SELECT * FROM (
+                            SELECT meta::id(id) AS username, *,
+                                time::format(dob, '%m-%d') AS day,
+                                time::format(dob, '%m-%d') < $from AS wrapped
+                            FROM birthday
+                         )
+                         WHERE $after_day = NONE
+                            OR wrapped > $after_wrapped
+                            OR (wrapped = $after_wrapped AND day > $after_day)
+                            OR (wrapped = $after_wrapped AND day = $after_day AND username > $after_username)
+                         ORDER BY wrapped, day, username
+                         LIMIT $limit",
+                    )
+                    .bind(("from", from.clone()))
+                    .bind(("after_day", after_day))
+                    .bind(("after_username", after_username))
+                    .bind(("after_wrapped", after_wrapped))
+            }
+        };
+
+        let mut response = query.bind(("limit", limit)).await?;
+        let page: Vec<SurrealBirthdayRecord> = response.take(0)?;
+
+        Ok(page.into_iter().map(Into::into).collect())
+    }
+
+    async fn count_birthdays(&self) -> Result<u64> {
+        let mut response = self
+            .db
+            .query("SELECT count() FROM birthday GROUP ALL")
+            .await?;
+        // There are no groups when the table is empty.
+        let count: Option<u64> = response.take((0, "count"))?;
+
+        Ok(count.unwrap_or_default())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.db.query("INFO FOR DB").await?.check()?;
+
+        Ok(())
+    }
+}
+
+/// `Birthday` with the date of birth stored as the SurrealDB datetime at midnight UTC,
+/// so the database enforces the valid dates.
+#[derive(serde::Serialize)]
+struct SurrealBirthday {
+    dob: Datetime,
+    timezone: Option<Tz>,
+}
+
+impl From<Birthday> for SurrealBirthday {
+    fn from(birthday: Birthday) -> Self {
+        SurrealBirthday {
+            dob: birthday.dob.and_time(NaiveTime::MIN).and_utc().into(),
+            timezone: birthday.timezone,
+        }
+    }
+}
+
+/// Birthday record read from the database, along with the username when it's
+/// selected by the query.
+#[derive(serde::Deserialize)]
+struct SurrealBirthdayRecord {
+    #[serde(default)]
+    username: String,
+    dob: Datetime,
+    #[serde(default)]
+    timezone: Option<Tz>,
+    /// The records written before the revisions were introduced have no revision
+    /// until the migrations are applied.
+    #[serde(default)]
+    revision: u64,
+}
+
+impl SurrealBirthdayRecord {
+    fn birthday(&self) -> Birthday {
+        let dob: DateTime<Utc> = self.dob.clone().into();
+        Birthday {
+            dob: NaiveDate::from(dob.naive_utc()),
+            timezone: self.timezone,
+        }
+    }
+}
+
+impl From<SurrealBirthdayRecord> for StoredBirthday {
+    fn from(record: SurrealBirthdayRecord) -> Self {
+        StoredBirthday {
+            birthday: record.birthday(),
+            revision: record.revision,
+        }
+    }
+}
+
+impl From<SurrealBirthdayRecord> for UserBirthday {
+    fn from(record: SurrealBirthdayRecord) -> Self {
+        UserBirthday {
+            birthday: record.birthday(),
+            username: record.username,
+        }
+    }
+}
+
+/// `IdempotencyRecord` with the expiration time stored as the SurrealDB datetime,
+/// so the records can be compared with the current time.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SurrealIdempotencyRecord {
+    fingerprint: String,
+    response: Option<StoredResponse>,
+    expires_at: Datetime,
+}
+
+impl From<IdempotencyRecord> for SurrealIdempotencyRecord {
+    fn from(record: IdempotencyRecord) -> Self {
+        SurrealIdempotencyRecord {
+            fingerprint: record.fingerprint,
+            response: record.response,
+            expires_at: record.expires_at.into(),
+        }
+    }
+}
+
+impl From<SurrealIdempotencyRecord> for IdempotencyRecord {
+    fn from(record: SurrealIdempotencyRecord) -> Self {
+        IdempotencyRecord {
+            fingerprint: record.fingerprint,
+            response: record.response,
+            expires_at: record.expires_at.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl<C: Connection> IdempotencyStore for SurrealBirthdayStore<C> {
+    async fn claim_key(
+        &self,
+        key: &str,
+        record: IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>> {
+        self.purge_expired(now).await?;
+
+        // The creation fails if the key is already claimed.
+        let created: surrealdb::Result<Option<SurrealIdempotencyRecord>> = self
+            .db
+            .create((IDEMPOTENCY_NS, key))
+            .content(SurrealIdempotencyRecord::from(record))
+            .await;
+        let Err(err) = created else {
+            return Ok(None);
+        };
+
+        let existing: Option<SurrealIdempotencyRecord> =
+            self.db.select((IDEMPOTENCY_NS, key)).await?;
+        match existing {
+            Some(existing) => Ok(Some(existing.into())),
+            None => Err(err.into()),
+        }
+    }
+
+    async fn complete_key(&self, key: &str, response: StoredResponse) -> Result<()> {
+        // The `UPDATE` of a missing record would create it, unless it's filtered out.
+        self.db
+            .query(
+                "UPDATE type::thing('idempotency', $key) SET response = $response
+                 WHERE fingerprint != NONE",
+            )
+            .bind(("key", key.to_owned()))
+            .bind(("response", response))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    async fn release_key(&self, key: &str) -> Result<()> {
+        let _record: Option<SurrealIdempotencyRecord> =
+            self.db.delete((IDEMPOTENCY_NS, key)).await?;
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64> {
+        let mut response = self
+            .db
+            .query("DELETE idempotency WHERE expires_at <= $now RETURN BEFORE")
+            .bind(("now", Datetime::from(now)))
+            .await?;
+        let purged: Vec<SurrealIdempotencyRecord> = response.take(0)?;
+
+        Ok(purged.len() as u64)
+    }
+}
+
+/// The snapshots are the SurrealQL scripts exported by the database. The export
+/// reads the data in a single transaction.
+#[async_trait]
+impl<C: Connection> BackupStore for SurrealBirthdayStore<C> {
+    async fn backup(&self) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let snapshot = self.db.export(()).await.map_err(backup_error)?;
+
+        Ok(snapshot.map_err(anyhow::Error::from).boxed())
+    }
+
+    async fn restore(&self, file: &Path) -> Result<()> {
+        self.db.import(file).await.map_err(backup_error)?;
+
+        Ok(())
+    }
+}
+
+/// SurrealDB makes the backups only with the embedded engines and over HTTP, the
+/// WebSocket protocol doesn't support them.
+fn backup_error(err: surrealdb::Error) -> anyhow::Error {
+    use surrealdb::error::Api;
+
+    match err {
+        surrealdb::Error::Api(Api::BackupsNotSupported) => BackupsNotSupported(
+            "The remote SurrealDB makes the backups only over HTTP, use the http:// or https:// endpoint",
+        )
+        .into(),
+        err => err.into(),
+    }
+}
+
+#[async_trait]
+impl<C: Connection> SchemaStore for SurrealBirthdayStore<C> {
+    fn migrations(&self) -> &'static [Migration] {
+        MIGRATIONS
+    }
+
+    async fn schema_version(&self) -> Result<u32> {
+        let mut response = self
+            .db
+            .query("SELECT VALUE version FROM schema_migration ORDER BY version DESC LIMIT 1")
+            .await?;
+        let version: Option<u32> = response.take(0)?;
+
+        Ok(version.unwrap_or_default())
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<bool> {
+        // The version is recorded first, so the migration of another instance
+        // conflicts with it and cancels the whole transaction.
+        let applied = async {
+            self.db
+                .query("BEGIN TRANSACTION")
+                .query(
+                    "CREATE type::thing('schema_migration', $version) CONTENT {
+                        version: $version,
+                        description: $description,
+                        applied_at: time::now()
+                    }",
+                )
+                .query(migration.script)
+                .query("COMMIT TRANSACTION")
+                .bind(("version", migration.version))
+                .bind(("description", migration.description))
+                .await?
+                .check()
+        }
+        .await;
+
+        match applied {
+            Ok(_) => Ok(true),
+            Err(_) if self.schema_version().await? >= migration.version => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
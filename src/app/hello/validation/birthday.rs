@@ -1,21 +1,33 @@
 use axum::{
     async_trait,
-    extract::{FromRequest, Request},
+    extract::{rejection::JsonRejection, FromRef, FromRequest, Request},
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::app::api::ApiError;
+use crate::app::{
+    api::{ApiError, FieldViolation, ViolationCode},
+    hello::birthday::Calendar,
+};
 
 use crate::app::hello::api::UserBirthdayRequest;
 
+lazy_static! {
+    /// Format of the date of birth, compiled once for all the requests.
+    static ref DATE_FORMAT: Regex = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+}
+
 /// Implement the `FromRequest` extractor for the `UserBirthdayRequest` struct.
 /// This will allow Axum to automatically deserialize the request body into a `UserBirthdayRequest` struct and validate it.
 #[async_trait]
 impl<S> FromRequest<S> for UserBirthdayRequest
 where
-    Json<UserBirthdayRequest>: FromRequest<S>,
+    Json<UserBirthdayRequest>: FromRequest<S, Rejection = JsonRejection>,
+    Calendar: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = Response;
@@ -23,56 +35,95 @@ where
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
         let body = Json::<UserBirthdayRequest>::from_request(req, state)
             .await
-            .map_err(IntoResponse::into_response)?;
+            .map_err(|rejection| ApiError::from(rejection).into_response())?;
 
         let Json(body) = body;
-        validate_birthday_request(body).map_err(IntoResponse::into_response)
+        let calendar = Calendar::from_ref(state);
+        validate_birthday_request(body, &calendar).map_err(IntoResponse::into_response)
     }
 }
 
 /// Validate the `UserBirthdayRequest` struct.
-/// If the validation fails, return an `ApiError`.
-fn validate_birthday_request(req: UserBirthdayRequest) -> Result<UserBirthdayRequest, ApiError> {
-    // Create a regex to validate the date format.
-    let re = Regex::new(r"^\d{4}-\d{2}-\d{2}$").map_err(|err| {
-        log::error!("Failed to create regex: {}", err);
-        ApiError::internal_server_error()
-    })?;
+/// The date of birth must be before today in the user's time zone.
+/// If the validation fails, return an `ApiError` listing all the invalid fields.
+pub(crate) fn validate_birthday_request(
+    req: UserBirthdayRequest,
+    calendar: &Calendar,
+) -> Result<UserBirthdayRequest, ApiError> {
+    let mut errors = vec![];
 
     // Validate the date format.
-    if !re.is_match(&req.date_of_birth) {
-        return Err(ApiError::bad_request(
+    let date = if !DATE_FORMAT.is_match(&req.date_of_birth) {
+        errors.push(FieldViolation::new(
+            "dateOfBirth",
+            ViolationCode::InvalidFormat,
             "Invalid date format. Valid format: YYYY-MM-DD",
         ));
-    }
-
-    let date: chrono::NaiveDate = req.date_of_birth.parse().map_err(|err| {
-        log::warn!("Failed to parse date: {}", err);
-        ApiError::bad_request("Invalid date")
-    })?;
+        None
+    } else {
+        match req.date_of_birth.parse::<NaiveDate>() {
+            Ok(date) => Some(date),
+            Err(err) => {
+                log::warn!("Failed to parse date: {}", err);
+                errors.push(FieldViolation::new(
+                    "dateOfBirth",
+                    ViolationCode::InvalidValue,
+                    "Invalid date",
+                ));
+                None
+            }
+        }
+    };
 
-    let today: chrono::NaiveDate = chrono::Local::now().date_naive();
+    let timezone = match req.timezone.as_deref().map(str::parse::<Tz>).transpose() {
+        Ok(timezone) => timezone,
+        Err(err) => {
+            log::warn!("Failed to parse time zone: {}", err);
+            errors.push(FieldViolation::new(
+                "timezone",
+                ViolationCode::InvalidValue,
+                "Invalid time zone. Use an IANA time zone name, e.g. Europe/London",
+            ));
+            None
+        }
+    };
 
     // Validate the date of birth.
-    if date >= today {
-        return Err(ApiError::bad_request(
-            "Invalid date of birth. The date should be before today.",
-        ));
+    if let Some(date) = date {
+        if date >= calendar.today_in(timezone) {
+            errors.push(FieldViolation::new(
+                "dateOfBirth",
+                ViolationCode::NotInPast,
+                "Invalid date of birth. The date should be before today.",
+            ));
+        }
     }
 
-    Ok(req)
+    if errors.is_empty() {
+        Ok(req)
+    } else {
+        Err(ApiError::validation(errors))
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use super::*;
+    use crate::app::{api::ErrorCode, clock::FixedClock, hello::birthday::LeapDayPolicy};
     use axum::{
         body::{to_bytes, Body},
         http::header,
     };
-    use chrono::Days;
 
-    use super::*;
+    /// Calendar with the clock stopped at 2024-06-15.
+    fn calendar() -> Calendar {
+        Calendar::new(
+            FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap()),
+            LeapDayPolicy::Feb28,
+            Tz::UTC,
+        )
+    }
 
     fn request(body: &str) -> Request<Body> {
         Request::builder()
@@ -91,7 +142,7 @@ mod tests {
     #[tokio::test]
     async fn test_post_request_validation_with_invalid_dob_format() {
         let request = request(r#"{ "dateOfBirth": "foo" }"#);
-        let result = UserBirthdayRequest::from_request(request, &()).await;
+        let result = UserBirthdayRequest::from_request(request, &calendar()).await;
         assert!(result.is_err());
 
         if let Err(res) = result {
@@ -106,15 +157,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_post_request_validation_with_dob_in_future() {
-        let tomorrow: chrono::NaiveDate = chrono::Local::now()
-            .date_naive()
-            .checked_add_days(Days::new(1))
-            .unwrap();
-
-        let body = format!(r#"{{ "dateOfBirth": "{}" }}"#, tomorrow);
-        let request = request(&body);
+        let request = request(r#"{ "dateOfBirth": "2024-06-15" }"#);
 
-        let result = UserBirthdayRequest::from_request(request, &()).await;
+        let result = UserBirthdayRequest::from_request(request, &calendar()).await;
         assert!(result.is_err());
 
         if let Err(res) = result {
@@ -130,11 +175,91 @@ mod tests {
     #[tokio::test]
     async fn test_post_request_valiation_with_valid_data() {
         let request = request(r#"{ "dateOfBirth": "2000-12-31" }"#);
-        let result = UserBirthdayRequest::from_request(request, &()).await;
+        let result = UserBirthdayRequest::from_request(request, &calendar()).await;
         assert!(result.is_ok());
 
         if let Ok(res) = result {
             assert_eq!(res.date_of_birth, "2000-12-31");
         }
     }
+
+    #[tokio::test]
+    async fn test_post_request_validation_with_invalid_timezone() {
+        let request = request(r#"{ "dateOfBirth": "2000-12-31", "timezone": "Mars/Olympus" }"#);
+        let result = UserBirthdayRequest::from_request(request, &calendar()).await;
+        assert!(result.is_err());
+
+        if let Err(res) = result {
+            let res = get_response_error(res).await;
+            assert_eq!(res.status, 400);
+            assert_eq!(
+                res.message,
+                "Invalid time zone. Use an IANA time zone name, e.g. Europe/London"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_request_validation_with_dob_today_in_timezone() {
+        // It's still June 14 in New York, when it's June 15 in UTC.
+        let req = request(r#"{ "dateOfBirth": "2024-06-14", "timezone": "America/New_York" }"#);
+        let result = UserBirthdayRequest::from_request(req, &calendar()).await;
+        assert!(result.is_err());
+
+        let req = request(r#"{ "dateOfBirth": "2024-06-14" }"#);
+        let result = UserBirthdayRequest::from_request(req, &calendar()).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_post_request_validation_lists_all_invalid_fields() {
+        let req = request(r#"{ "dateOfBirth": "2000-13-01", "timezone": "Mars/Olympus" }"#);
+        let result = UserBirthdayRequest::from_request(req, &calendar()).await;
+        assert!(result.is_err());
+
+        if let Err(res) = result {
+            let res = get_response_error(res).await;
+            assert_eq!(res.code, ErrorCode::ValidationFailed);
+            let fields: Vec<_> = res
+                .errors
+                .iter()
+                .map(|violation| (violation.field.as_str(), violation.code))
+                .collect();
+            assert_eq!(
+                fields,
+                vec![
+                    ("dateOfBirth", ViolationCode::InvalidValue),
+                    ("timezone", ViolationCode::InvalidValue),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_request_validation_with_invalid_json() {
+        let req = request(r#"{ "dateOfBirth": "#);
+        let result = UserBirthdayRequest::from_request(req, &calendar()).await;
+        assert!(result.is_err());
+
+        if let Err(res) = result {
+            assert_eq!(
+                res.headers()[header::CONTENT_TYPE],
+                "application/problem+json"
+            );
+            let res = get_response_error(res).await;
+            assert_eq!(res.status, 400);
+            assert_eq!(res.code, ErrorCode::BadRequest);
+        }
+
+        let req = request(r#"{ "timezone": "Europe/London" }"#);
+        let result = UserBirthdayRequest::from_request(req, &calendar()).await;
+        assert!(result.is_err());
+
+        if let Err(res) = result {
+            let res = get_response_error(res).await;
+            assert_eq!(res.status, 422);
+            assert_eq!(res.code, ErrorCode::InvalidBody);
+            assert!(res.message.contains("missing field `dateOfBirth`"));
+        }
+    }
 }
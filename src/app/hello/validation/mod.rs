@@ -1,4 +1,5 @@
 mod birthday;
 mod username;
 
+pub(crate) use birthday::validate_birthday_request;
 pub use username::*;
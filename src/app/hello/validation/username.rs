@@ -1,20 +1,195 @@
+use anyhow::{bail, Result};
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Path},
+    extract::{FromRef, FromRequestParts, Path},
     response::{IntoResponse, Response},
 };
 use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::app::api::ApiError;
+use crate::app::api::{ApiError, FieldViolation, ViolationCode};
 
 pub struct ValidatedUsername(pub String);
 
+/// Class of the characters allowed in the usernames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum CharClass {
+    /// Letters of the Latin alphabet without the diacritics, `a-z` and `A-Z`.
+    AsciiLetters,
+    /// Letters of any script, along with their combining marks.
+    Letters,
+    /// Decimal digits of any script.
+    Digits,
+    Underscore,
+    Hyphen,
+    Period,
+}
+
+impl CharClass {
+    /// The class in the regex character class syntax.
+    fn pattern(&self) -> &'static str {
+        match self {
+            CharClass::AsciiLetters => "a-zA-Z",
+            CharClass::Letters => r"\p{L}\p{M}",
+            CharClass::Digits => r"\p{Nd}",
+            CharClass::Underscore => "_",
+            CharClass::Hyphen => r"\-",
+            CharClass::Period => ".",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            CharClass::AsciiLetters => "ASCII letters",
+            CharClass::Letters => "letters",
+            CharClass::Digits => "digits",
+            CharClass::Underscore => "underscores",
+            CharClass::Hyphen => "hyphens",
+            CharClass::Period => "periods",
+        }
+    }
+}
+
+/// Rules the usernames must follow. The usernames are canonicalized before they're
+/// validated, so the different spellings of the same name map to one record.
+///
+/// The rules are compiled once on startup and shared with the handlers through
+/// the axum state.
+#[derive(Debug, Clone)]
+pub(crate) struct UsernamePolicy {
+    allowed: Regex,
+    /// Human readable list of the allowed characters, used in the error message.
+    allowed_description: String,
+    min_length: usize,
+    max_length: usize,
+    /// Normalize the usernames to the Unicode Normalization Form C.
+    normalize: bool,
+    /// Fold the case of the usernames, so "Foo" and "foo" are the same user.
+    case_fold: bool,
+}
+
+impl UsernamePolicy {
+    /// Compile the policy.
+    ///
+    /// # Args
+    ///
+    /// - `allowed`: Classes of the characters allowed in the usernames
+    /// - `min_length` and `max_length`: Bounds of the username length, in characters
+    /// - `normalize`: Normalize the usernames to the NFC form
+    /// - `case_fold`: Fold the case of the usernames
+    pub(crate) fn new(
+        allowed: &[CharClass],
+        min_length: usize,
+        max_length: usize,
+        normalize: bool,
+        case_fold: bool,
+    ) -> Result<Self> {
+        if allowed.is_empty() {
+            bail!("At least one class of the characters must be allowed in the usernames");
+        }
+        if min_length == 0 || min_length > max_length {
+            bail!(
+                "Invalid username length bounds: {}..{}. The minimal length must be positive and not greater than the maximal length",
+                min_length,
+                max_length
+            );
+        }
+
+        let classes: String = allowed.iter().map(CharClass::pattern).collect();
+        let allowed_regex = Regex::new(&format!("^[{}]+$", classes))?;
+
+        let descriptions: Vec<_> = allowed.iter().map(CharClass::description).collect();
+        let allowed_description = match descriptions.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => unreachable!("The classes are checked to be non-empty"),
+        };
+
+        Ok(UsernamePolicy {
+            allowed: allowed_regex,
+            allowed_description,
+            min_length,
+            max_length,
+            normalize,
+            case_fold,
+        })
+    }
+
+    /// Bring the username to the canonical form it's stored in.
+    pub(crate) fn canonicalize(&self, username: &str) -> String {
+        let username = if self.case_fold {
+            caseless::default_case_fold_str(username)
+        } else {
+            username.to_owned()
+        };
+
+        if self.normalize {
+            username.nfc().collect()
+        } else {
+            username
+        }
+    }
+
+    /// Canonicalize and validate the username.
+    pub(crate) fn validate(&self, username: &str) -> Result<String, ApiError> {
+        if username.is_empty() {
+            return Err(ApiError::invalid_field(
+                "username",
+                ViolationCode::Required,
+                "Username should not be empty.",
+            ));
+        }
+
+        let username = self.canonicalize(username);
+        let mut errors = vec![];
+
+        if !self.allowed.is_match(&username) {
+            errors.push(FieldViolation::new(
+                "username",
+                ViolationCode::InvalidCharacters,
+                &format!(
+                    "Invalid username. Only {} are allowed.",
+                    self.allowed_description
+                ),
+            ));
+        }
+
+        let length = username.chars().count();
+        if !(self.min_length..=self.max_length).contains(&length) {
+            errors.push(FieldViolation::new(
+                "username",
+                ViolationCode::InvalidLength,
+                &format!(
+                    "Invalid username. The username should be between {} and {} characters long.",
+                    self.min_length, self.max_length
+                ),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(username)
+        } else {
+            Err(ApiError::validation(errors))
+        }
+    }
+}
+
+impl Default for UsernamePolicy {
+    /// Unicode letters, normalized to NFC, without the case folding.
+    fn default() -> Self {
+        UsernamePolicy::new(&[CharClass::Letters], 1, 64, true, false)
+            .expect("The default username policy is valid")
+    }
+}
+
 /// Implement the `FromRequest` extractor for the `ValidatedUsername` struct.
 /// This will allow Axum to automatically validate the username and extract it from the request path.
+/// The extracted username is in the canonical form of the `UsernamePolicy`.
 #[async_trait]
 impl<S> FromRequestParts<S> for ValidatedUsername
 where
     Path<String>: FromRequestParts<S>,
+    UsernamePolicy: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = Response;
@@ -29,32 +204,14 @@ where
 
         let Path(username) = username;
 
-        validate_username(&username)
-            .await
+        let username = UsernamePolicy::from_ref(state)
+            .validate(&username)
             .map_err(IntoResponse::into_response)?;
 
         Ok(ValidatedUsername(username))
     }
 }
 
-/// The actual validation logic for the username.
-async fn validate_username(username: &str) -> Result<(), ApiError> {
-    let re = Regex::new(r"^[a-zA-Z]+$").map_err(|err| {
-        log::error!("Failed to create regex: {}", err);
-        ApiError::internal_server_error()
-    })?;
-    if username.is_empty() {
-        return Err(ApiError::bad_request("Username should not be empty."));
-    }
-
-    if !re.is_match(username) {
-        return Err(ApiError::bad_request(
-            "Invalid username. Only letters are allowed.",
-        ));
-    }
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
 
@@ -76,15 +233,13 @@ mod tests {
             .unwrap()
     }
 
-    fn router() -> Router {
-        Router::new().route(
-            "/hello/:username",
-            get(
-                |ValidatedUsername(username): ValidatedUsername| async move {
-                    assert!(!username.is_empty());
-                },
-            ),
-        )
+    fn router(policy: UsernamePolicy) -> Router {
+        Router::new()
+            .route(
+                "/hello/:username",
+                get(|ValidatedUsername(username): ValidatedUsername| async move { username }),
+            )
+            .with_state(policy)
     }
 
     /// Map the response body to an `ApiError` struct.
@@ -95,17 +250,77 @@ mod tests {
 
     #[tokio::test]
     async fn test_username_validation_with_valid_user() {
-        let res = router().oneshot(request("foo")).await.unwrap();
+        let res = router(UsernamePolicy::default())
+            .oneshot(request("foo"))
+            .await
+            .unwrap();
 
         assert_eq!(res.status(), StatusCode::OK);
     }
 
     #[tokio::test]
     async fn test_username_validation_with_invalid_username() {
-        let res = router().oneshot(request("foo-bar")).await.unwrap();
+        let res = router(UsernamePolicy::default())
+            .oneshot(request("foo-bar"))
+            .await
+            .unwrap();
         assert_eq!(res.status(), StatusCode::BAD_REQUEST);
         let res = get_response_error(res).await;
         assert_eq!(res.status, 400);
         assert_eq!(res.message, "Invalid username. Only letters are allowed.");
     }
+
+    #[tokio::test]
+    async fn test_username_validation_canonicalizes_username() {
+        let policy = UsernamePolicy::new(&[CharClass::Letters], 1, 64, true, true).unwrap();
+
+        // "Zoë" with the decomposed "ë", percent-encoded in the path.
+        let res = router(policy).oneshot(request("Zoe%CC%88")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), "zo\u{eb}");
+    }
+
+    #[test]
+    fn test_username_policy() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.validate("Łukasz").unwrap(), "Łukasz");
+        assert_eq!(policy.validate("Zoë").unwrap(), "Zoë");
+        assert!(policy.validate("foo1").is_err());
+        assert!(policy.validate(&"a".repeat(65)).is_err());
+
+        let policy = UsernamePolicy::new(
+            &[
+                CharClass::AsciiLetters,
+                CharClass::Digits,
+                CharClass::Underscore,
+            ],
+            3,
+            8,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(policy.validate("foo_1").unwrap(), "foo_1");
+        assert!(policy.validate("Łukasz").is_err());
+        assert_eq!(
+            policy.validate("fo").unwrap_err().message,
+            "Invalid username. The username should be between 3 and 8 characters long."
+        );
+        assert_eq!(
+            policy.validate("foo-bar").unwrap_err().message,
+            "Invalid username. Only ASCII letters, digits and underscores are allowed."
+        );
+
+        let policy = UsernamePolicy::new(&[CharClass::Letters], 1, 64, true, true).unwrap();
+        assert_eq!(policy.validate("Foo").unwrap(), "foo");
+        assert_eq!(policy.validate("STRAßE").unwrap(), "strasse");
+    }
+
+    #[test]
+    fn test_invalid_username_policy() {
+        assert!(UsernamePolicy::new(&[], 1, 64, true, false).is_err());
+        assert!(UsernamePolicy::new(&[CharClass::Letters], 0, 64, true, false).is_err());
+        assert!(UsernamePolicy::new(&[CharClass::Letters], 10, 5, true, false).is_err());
+    }
 }
@@ -0,0 +1,5 @@
+birthday-today = Hallo, { $username }! Alles Gute zum Geburtstag!
+birthday-in-days = Hallo, { $username }! Dein Geburtstag ist in { $days ->
+        [one] { $days } Tag
+       *[other] { $days } Tagen
+    }
@@ -0,0 +1,5 @@
+birthday-today = Hello, { $username }! Happy birthday!
+birthday-in-days = Hello, { $username }! Your birthday is in { $days ->
+        [one] { $days } day
+       *[other] { $days } days
+    }
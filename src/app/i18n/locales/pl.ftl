@@ -0,0 +1,5 @@
+birthday-today = Cześć, { $username }! Wszystkiego najlepszego z okazji urodzin!
+birthday-in-days = Cześć, { $username }! Twoje urodziny są za { $days ->
+        [one] { $days } dzień
+       *[other] { $days } dni
+    }
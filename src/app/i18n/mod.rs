@@ -0,0 +1,285 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use unic_langid::LanguageIdentifier;
+
+/// Message catalogs embedded in the binary, in the Fluent format.
+/// See <https://projectfluent.org/fluent/guide/> for the syntax.
+static LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("locales/en.ftl")),
+    ("de", include_str!("locales/de.ftl")),
+    ("pl", include_str!("locales/pl.ftl")),
+];
+
+/// Translations of the user-facing messages.
+/// The catalogs are parsed once on startup and shared with the handlers through
+/// the axum state.
+#[derive(Clone)]
+pub(crate) struct Catalog {
+    bundles: Arc<HashMap<LanguageIdentifier, FluentBundle<FluentResource>>>,
+    available: Arc<Vec<LanguageIdentifier>>,
+    fallback: LanguageIdentifier,
+}
+
+impl Catalog {
+    /// Parse the embedded catalogs.
+    ///
+    /// # Args
+    ///
+    /// - `fallback`: Locale used when none of the locales requested by the client
+    ///   is available. It must be one of the embedded locales
+    pub(crate) fn new(fallback: LanguageIdentifier) -> Result<Self> {
+        let mut bundles = HashMap::new();
+        let mut available = vec![];
+
+        for (locale, source) in LOCALES {
+            let locale: LanguageIdentifier = locale.parse()?;
+            let resource = FluentResource::try_new(source.to_string())
+                .map_err(|(_, errors)| anyhow!("Invalid '{}' catalog: {:?}", locale, errors))?;
+
+            let mut bundle = FluentBundle::new_concurrent(vec![locale.clone()]);
+            // The messages are plain text, there is no need for the bidi isolation marks.
+            bundle.set_use_isolating(false);
+            bundle
+                .add_resource(resource)
+                .map_err(|errors| anyhow!("Invalid '{}' catalog: {:?}", locale, errors))?;
+
+            bundles.insert(locale.clone(), bundle);
+            available.push(locale);
+        }
+
+        if !bundles.contains_key(&fallback) {
+            bail!(
+                "There are no messages for the fallback locale '{}'. Available locales: {}",
+                fallback,
+                available
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(Catalog {
+            bundles: Arc::new(bundles),
+            available: Arc::new(available),
+            fallback,
+        })
+    }
+
+    /// Select the best available locale for the value of the `Accept-Language` header.
+    pub(crate) fn negotiate(&self, accept_language: Option<&str>) -> Messages {
+        let requested = accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default();
+        let locale = negotiate_languages(
+            &requested,
+            &self.available,
+            Some(&self.fallback),
+            NegotiationStrategy::Lookup,
+        )
+        .first()
+        .map(|locale| (*locale).clone())
+        .unwrap_or_else(|| self.fallback.clone());
+
+        Messages {
+            catalog: self.clone(),
+            locale,
+        }
+    }
+}
+
+/// Messages in the locale negotiated for the request.
+#[derive(Clone)]
+pub(crate) struct Messages {
+    catalog: Catalog,
+    locale: LanguageIdentifier,
+}
+
+impl Messages {
+    pub(crate) fn locale(&self) -> &LanguageIdentifier {
+        &self.locale
+    }
+
+    /// Format the message with the given arguments.
+    /// Falls back to the message ID if the message is missing, so the error doesn't
+    /// reach the client.
+    pub(crate) fn format(&self, id: &str, args: &FluentArgs) -> String {
+        let bundle = &self.catalog.bundles[&self.locale];
+        let Some(pattern) = bundle.get_message(id).and_then(|msg| msg.value()) else {
+            log::error!(
+                "Message '{}' is missing in the '{}' catalog",
+                id,
+                self.locale
+            );
+            return id.to_owned();
+        };
+
+        let mut errors = vec![];
+        let message = bundle.format_pattern(pattern, Some(args), &mut errors);
+        if !errors.is_empty() {
+            log::error!(
+                "Failed to format message '{}' in the '{}' catalog: {:?}",
+                id,
+                self.locale,
+                errors
+            );
+        }
+
+        message.into_owned()
+    }
+}
+
+/// Negotiate the locale of the messages with the `Accept-Language` header.
+#[async_trait]
+impl<S> FromRequestParts<S> for Messages
+where
+    Catalog: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let accept_language = parts
+            .headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+
+        Ok(Catalog::from_ref(state).negotiate(accept_language))
+    }
+}
+
+/// Response translated to the negotiated locale. `Content-Language` names the locale,
+/// and `Vary: Accept-Language` keeps the caches from serving one translation for
+/// the other.
+pub(crate) struct Localized<T>(pub LanguageIdentifier, pub T);
+
+impl<T: IntoResponse> IntoResponse for Localized<T> {
+    fn into_response(self) -> Response {
+        let Localized(locale, body) = self;
+        let mut res = body.into_response();
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_LANGUAGE,
+            HeaderValue::from_str(&locale.to_string()).expect("The language tag is a valid header"),
+        );
+        headers.append(header::VARY, HeaderValue::from_static("accept-language"));
+        res
+    }
+}
+
+/// Parse the `Accept-Language` header into the list of the locales ordered by
+/// the preference. The invalid entries and the wildcard are skipped.
+fn parse_accept_language(value: &str) -> Vec<LanguageIdentifier> {
+    let mut locales: Vec<(f32, LanguageIdentifier)> = value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let locale = params.next()?.trim().parse().ok()?;
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+
+            (quality > 0.0).then_some((quality, locale))
+        })
+        .collect();
+
+    // The sort is stable, the locales with the same quality keep their order.
+    locales.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    locales.into_iter().map(|(_, locale)| locale).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        Catalog::new("en".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_accept_language() {
+        let locales = parse_accept_language("de;q=0.5, pl-PL, *;q=0.1, en;q=0, fr;q=0.8");
+
+        assert_eq!(
+            locales,
+            vec![
+                "pl-PL".parse::<LanguageIdentifier>().unwrap(),
+                "fr".parse().unwrap(),
+                "de".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let catalog = catalog();
+        let locale = |header| catalog.negotiate(header).locale().to_string();
+
+        assert_eq!(locale(None), "en");
+        assert_eq!(locale(Some("pl-PL,en;q=0.5")), "pl");
+        assert_eq!(locale(Some("fr-FR, de;q=0.9")), "de");
+        assert_eq!(locale(Some("fr-FR")), "en");
+    }
+
+    #[test]
+    fn test_localized_response() {
+        let res = Localized("pl".parse().unwrap(), ()).into_response();
+
+        assert_eq!(res.headers()[header::CONTENT_LANGUAGE], "pl");
+        assert_eq!(res.headers()[header::VARY], "accept-language");
+    }
+
+    #[test]
+    fn test_unknown_fallback_locale() {
+        assert!(Catalog::new("fr".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_plural_rules() {
+        let catalog = catalog();
+        let in_days = |locale, days: i64| {
+            let mut args = FluentArgs::new();
+            args.set("username", "foo");
+            args.set("days", days);
+            catalog
+                .negotiate(Some(locale))
+                .format("birthday-in-days", &args)
+        };
+
+        assert_eq!(in_days("en", 1), "Hello, foo! Your birthday is in 1 day");
+        assert_eq!(in_days("en", 2), "Hello, foo! Your birthday is in 2 days");
+        assert_eq!(in_days("pl", 1), "Cześć, foo! Twoje urodziny są za 1 dzień");
+        assert_eq!(in_days("pl", 22), "Cześć, foo! Twoje urodziny są za 22 dni");
+        assert_eq!(
+            in_days("de", 5),
+            "Hallo, foo! Dein Geburtstag ist in 5 Tagen"
+        );
+    }
+
+    /// Every catalog must contain all the messages used by the handlers.
+    #[test]
+    fn test_catalogs_are_complete() {
+        let catalog = catalog();
+
+        for (locale, bundle) in catalog.bundles.iter() {
+            for id in ["birthday-today", "birthday-in-days"] {
+                assert!(
+                    bundle.has_message(id),
+                    "Message '{}' is missing in the '{}' catalog",
+                    id,
+                    locale
+                );
+            }
+        }
+    }
+}
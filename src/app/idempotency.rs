@@ -0,0 +1,489 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    RequestExt,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::app::{
+    api::{ApiError, ErrorCode, ViolationCode},
+    clock::Clock,
+    Store,
+};
+use crate::setup::auth::Principal;
+
+/// Header with the key identifying the retries of the same request.
+pub(crate) static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Header set on the replayed responses.
+static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Maximal length of the idempotency key. UUIDs are recommended.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Headers of the response which are stored and replayed.
+/// The others, e.g. `x-request-id`, belong to the particular request.
+static REPLAYED_HEADERS: &[HeaderName] = &[header::CONTENT_TYPE, header::ETAG];
+
+/// Response of the write request, replayed when the request is retried.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// Request made with the idempotency key.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct IdempotencyRecord {
+    /// Hash of the method, the URI and the body of the request. The key must not be
+    /// reused for a different request.
+    pub fingerprint: String,
+    /// The response is not set while the request is being handled.
+    pub response: Option<StoredResponse>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Storage of the idempotency keys along with the responses of the requests.
+#[async_trait]
+pub(crate) trait IdempotencyStore: Send + Sync {
+    /// Claim the key for the request, unless it's held by another record which hasn't
+    /// expired at `now`. The check and the write are atomic. The expired records are
+    /// removed.
+    ///
+    /// Returns the record holding the key, or `None` if the key was claimed.
+    async fn claim_key(
+        &self,
+        key: &str,
+        record: IdempotencyRecord,
+        now: DateTime<Utc>,
+    ) -> Result<Option<IdempotencyRecord>>;
+    /// Store the response of the request which claimed the key.
+    async fn complete_key(&self, key: &str, response: StoredResponse) -> Result<()>;
+    /// Release the key, so the request can be retried, e.g. when it failed.
+    async fn release_key(&self, key: &str) -> Result<()>;
+    /// Remove all the records expired at `now`. Returns the number of removed records.
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64>;
+}
+
+/// How long the responses are kept for the retries.
+#[derive(Clone)]
+pub(crate) struct IdempotencyPolicy {
+    clock: Arc<dyn Clock>,
+    ttl: Duration,
+}
+
+impl IdempotencyPolicy {
+    pub(crate) fn new(clock: impl Clock + 'static, ttl: Duration) -> Self {
+        IdempotencyPolicy {
+            clock: Arc::new(clock),
+            ttl,
+        }
+    }
+}
+
+/// Tower middleware replaying the response of the write request when it's retried
+/// with the same `Idempotency-Key` header. The requests without the header are passed
+/// through.
+///
+/// - The key reused for a different request is rejected with `422 Unprocessable Entity`.
+/// - The retry of the request which is still being handled is rejected with
+///   `409 Conflict`.
+/// - The server errors are not stored, so the request can be retried.
+///
+/// The keys are chosen by the clients, so each principal and route has its own keys.
+/// The body is read with the limit of the route, see `DefaultBodyLimit`.
+pub(crate) async fn idempotency(
+    State(store): State<Store>,
+    State(policy): State<IdempotencyPolicy>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.headers().get(&IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let key = match validate_key(key) {
+        Ok(key) => key,
+        Err(err) => return err.into_response(),
+    };
+    let key = scoped_key(
+        request.extensions().get::<Principal>(),
+        request.method(),
+        request.uri().path(),
+        &key,
+    );
+
+    let (parts, body) = request.with_limited_body().into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            log::debug!("Failed to read the request body: {}", err);
+            return ApiError::from_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The request body is too large",
+            )
+            .into_response();
+        }
+    };
+
+    let fingerprint = fingerprint(&parts.method, &parts.uri, &body);
+    let now = policy.clock.now();
+    let record = IdempotencyRecord {
+        fingerprint: fingerprint.clone(),
+        response: None,
+        expires_at: now + policy.ttl,
+    };
+
+    match store.idempotency.claim_key(&key, record, now).await {
+        Ok(None) => {}
+        Ok(Some(existing)) if existing.fingerprint != fingerprint => {
+            return ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::IdempotencyKeyReused,
+                "The idempotency key was already used for a different request",
+            )
+            .into_response();
+        }
+        Ok(Some(IdempotencyRecord {
+            response: Some(response),
+            ..
+        })) => {
+            log::debug!("Replaying the response for the idempotency key: {}", &key);
+            return replay(response);
+        }
+        Ok(Some(_)) => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                ErrorCode::IdempotencyKeyInUse,
+                "The request with the same idempotency key is still being processed",
+            )
+            .into_response();
+        }
+        Err(err) => return ApiError::from(err).into_response(),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("Failed to read the response body: {}", err);
+            release(&store, &key).await;
+            return ApiError::internal_server_error().into_response();
+        }
+    };
+
+    match std::str::from_utf8(&body) {
+        Ok(text) if !parts.status.is_server_error() => {
+            let stored = StoredResponse {
+                status: parts.status.as_u16(),
+                headers: REPLAYED_HEADERS
+                    .iter()
+                    .filter_map(|name| {
+                        let value = parts.headers.get(name)?.to_str().ok()?;
+                        Some((name.to_string(), value.to_owned()))
+                    })
+                    .collect(),
+                body: text.to_owned(),
+            };
+            if let Err(err) = store.idempotency.complete_key(&key, stored).await {
+                log::error!(
+                    "Failed to store the response of the idempotent request: {:?}",
+                    err
+                );
+            }
+        }
+        _ => release(&store, &key).await,
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn validate_key(key: &HeaderValue) -> Result<String, ApiError> {
+    let invalid = || {
+        ApiError::invalid_field(
+            "Idempotency-Key",
+            ViolationCode::InvalidValue,
+            &format!(
+                "Invalid idempotency key. The key should be between 1 and {} visible ASCII characters long.",
+                MAX_KEY_LENGTH
+            ),
+        )
+    };
+
+    let key = key.to_str().map_err(|_| invalid())?;
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(invalid());
+    }
+
+    Ok(key.to_owned())
+}
+
+/// Key of the record of the request, the client's key scoped to the principal, the
+/// method and the path, so the clients can't replay the responses of the others.
+/// None of the parts but the principal may contain the line feed, so the parts can't
+/// be shifted to make the keys of two scopes equal.
+fn scoped_key(principal: Option<&Principal>, method: &Method, path: &str, key: &str) -> String {
+    let principal = principal.map_or("", |principal| principal.name.as_str());
+    let mut hasher = Sha256::new();
+    for part in [principal, method.as_str(), path, key] {
+        hasher.update(part);
+        hasher.update(b"\n");
+    }
+
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// Hash of the method, the URI and the body of the request.
+fn fingerprint(method: &axum::http::Method, uri: &axum::http::Uri, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update(b"\n");
+    hasher.update(uri.to_string());
+    hasher.update(b"\n");
+    hasher.update(body);
+
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, stored.body).into_response();
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(
+        IDEMPOTENT_REPLAYED.clone(),
+        HeaderValue::from_static("true"),
+    );
+
+    response
+}
+
+async fn release(store: &Store, key: &str) {
+    if let Err(err) = store.idempotency.release_key(key).await {
+        log::error!("Failed to release the idempotency key: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{
+        extract::{DefaultBodyLimit, FromRef},
+        middleware,
+        routing::put,
+        Router,
+    };
+    use chrono::NaiveDate;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::app::{clock::FixedClock, hello::store::InMemoryBirthdayStore};
+
+    #[derive(Clone)]
+    struct TestState {
+        store: Store,
+        policy: IdempotencyPolicy,
+    }
+
+    impl FromRef<TestState> for Store {
+        fn from_ref(state: &TestState) -> Self {
+            state.store.clone()
+        }
+    }
+
+    impl FromRef<TestState> for IdempotencyPolicy {
+        fn from_ref(state: &TestState) -> Self {
+            state.policy.clone()
+        }
+    }
+
+    /// Router echoing the body of the request. It counts the handled requests and fails
+    /// the requests with the `fail` body.
+    fn router(calls: Arc<AtomicUsize>) -> Router {
+        let state = TestState {
+            store: Store::new(InMemoryBirthdayStore::default()),
+            policy: IdempotencyPolicy::new(
+                FixedClock::on(NaiveDate::from_ymd_opt(2024, 6, 15).unwrap()),
+                Duration::hours(24),
+            ),
+        };
+
+        Router::new()
+            .route(
+                "/hello/:username",
+                put(move |body: String| async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    if body == "fail" {
+                        return ApiError::internal_server_error().into_response();
+                    }
+                    (StatusCode::CREATED, [(header::ETAG, "\"1\"")], body).into_response()
+                }),
+            )
+            .layer(middleware::from_fn_with_state(state.clone(), idempotency))
+            .layer(DefaultBodyLimit::max(MAX_BODY))
+            .with_state(state)
+    }
+
+    /// Body limit of the test router.
+    const MAX_BODY: usize = 16;
+
+    fn request(uri: &str, key: Option<&str>, body: impl Into<Body>) -> Request {
+        let mut request = Request::put(uri);
+        if let Some(key) = key {
+            request = request.header(&IDEMPOTENCY_KEY, key);
+        }
+        request.body(body.into()).unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay_response() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = router(calls.clone());
+
+        let res = app
+            .clone()
+            .oneshot(request("/hello/foo", Some("key"), "foo"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(!res.headers().contains_key(&IDEMPOTENT_REPLAYED));
+        assert_eq!(body(res).await, "foo");
+
+        let res = app
+            .clone()
+            .oneshot(request("/hello/foo", Some("key"), "foo"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()[&IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(res.headers()[header::ETAG], "\"1\"");
+        assert_eq!(body(res).await, "foo");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The requests without the key are not replayed.
+        let res = app
+            .oneshot(request("/hello/foo", None, "foo"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_reused_for_different_request() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = router(calls.clone());
+
+        let res = app
+            .clone()
+            .oneshot(request("/hello/foo", Some("key"), "foo"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = app
+            .clone()
+            .oneshot(request("/hello/foo", Some("key"), "bar"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_scoped() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = router(calls.clone());
+        let with_principal = |name: &str| {
+            let mut request = request("/hello/foo", Some("key"), "foo");
+            request.extensions_mut().insert(Principal {
+                name: name.to_owned(),
+                scopes: vec![],
+            });
+            request
+        };
+
+        for request in [
+            request("/hello/foo", Some("key"), "foo"),
+            request("/hello/bar", Some("key"), "foo"),
+            with_principal("alice"),
+            with_principal("bob"),
+        ] {
+            let res = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert!(!res.headers().contains_key(&IDEMPOTENT_REPLAYED));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let res = app.oneshot(with_principal("alice")).await.unwrap();
+        assert_eq!(res.headers()[&IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = router(calls.clone());
+        let body = "a".repeat(MAX_BODY + 1);
+
+        let res = app
+            .oneshot(request("/hello/foo", Some("key"), body))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_not_replayed() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = router(calls.clone());
+
+        for _ in 0..2 {
+            let res = app
+                .clone()
+                .oneshot(request("/hello/foo", Some("key"), "fail"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_key() {
+        let app = router(Arc::new(AtomicUsize::new(0)));
+        let long_key = "a".repeat(MAX_KEY_LENGTH + 1);
+
+        for key in ["", "foo bar", long_key.as_str()] {
+            let res = app
+                .clone()
+                .oneshot(request("/hello/foo", Some(key), "foo"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{bail, Result};
+use axum::async_trait;
+
+/// A single versioned change of the database schema.
+#[derive(Debug)]
+pub(crate) struct Migration {
+    /// Version of the schema after the migration is applied. Versions start at 1
+    /// and must be strictly increasing.
+    pub version: u32,
+    pub description: &'static str,
+    /// Script in the query language of the backend.
+    pub script: &'static str,
+}
+
+/// Storage backend that keeps its schema under version control.
+#[async_trait]
+pub(crate) trait SchemaStore: Send + Sync {
+    /// All the migrations known to this binary, ordered by version.
+    fn migrations(&self) -> &'static [Migration];
+
+    /// The latest version applied to the database, `0` if there was none.
+    async fn schema_version(&self) -> Result<u32>;
+
+    /// Apply the migration and record its version in the database, unless another
+    /// instance of the application sharing the database applied it in the meantime.
+    ///
+    /// Returns `false` if the migration was already applied.
+    async fn apply_migration(&self, migration: &Migration) -> Result<bool>;
+}
+
+/// Bring the database schema up to date.
+///
+/// Fails if the database was migrated by a newer version of the application, as
+/// this binary doesn't know how to work with such schema. The instances sharing the
+/// database may migrate it at the same time, each migration is applied once.
+pub(crate) async fn migrate(store: &dyn SchemaStore) -> Result<()> {
+    let migrations = store.migrations();
+    let latest = migrations.last().map(|m| m.version).unwrap_or_default();
+    let current = store.schema_version().await?;
+
+    if current > latest {
+        bail!(
+            "The database schema version {} is newer than the latest version {} supported by this binary",
+            current,
+            latest
+        );
+    }
+
+    let pending = migrations.iter().filter(|m| m.version > current);
+    for migration in pending {
+        log::info!(
+            "Applying migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        if !store.apply_migration(migration).await? {
+            log::info!(
+                "Migration {} was applied by another instance",
+                migration.version
+            );
+        }
+    }
+
+    log::info!("Database schema is at version {}", latest);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    static MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "first",
+            script: "",
+        },
+        Migration {
+            version: 2,
+            description: "second",
+            script: "",
+        },
+    ];
+
+    struct FakeSchemaStore {
+        applied: Mutex<Vec<u32>>,
+    }
+
+    impl FakeSchemaStore {
+        fn at_version(version: u32) -> Self {
+            FakeSchemaStore {
+                applied: Mutex::new((1..=version).collect()),
+            }
+        }
+
+        fn applied(&self) -> Vec<u32> {
+            self.applied.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl SchemaStore for FakeSchemaStore {
+        fn migrations(&self) -> &'static [Migration] {
+            MIGRATIONS
+        }
+
+        async fn schema_version(&self) -> Result<u32> {
+            Ok(self.applied().last().copied().unwrap_or_default())
+        }
+
+        async fn apply_migration(&self, migration: &Migration) -> Result<bool> {
+            let mut applied = self.applied.lock().unwrap();
+            if applied.contains(&migration.version) {
+                return Ok(false);
+            }
+            applied.push(migration.version);
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_applies_pending_migrations() {
+        let store = FakeSchemaStore::at_version(1);
+
+        migrate(&store).await.unwrap();
+
+        assert_eq!(store.applied(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_is_idempotent() {
+        let store = FakeSchemaStore::at_version(0);
+
+        migrate(&store).await.unwrap();
+        migrate(&store).await.unwrap();
+
+        assert_eq!(store.applied(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_refuses_newer_schema() {
+        let store = FakeSchemaStore::at_version(3);
+
+        let res = migrate(&store).await;
+
+        assert!(res.is_err());
+        assert_eq!(store.applied(), vec![1, 2, 3]);
+    }
+}
@@ -1,6 +1,15 @@
 pub(crate) mod api;
+pub(crate) mod audit;
+pub(crate) mod backup;
+pub(crate) mod clock;
 pub(crate) mod health;
 pub(crate) mod hello;
+pub(crate) mod i18n;
+pub(crate) mod idempotency;
+pub(crate) mod migrations;
+pub(crate) mod retry;
+pub(crate) mod state;
 pub(crate) mod store;
 
+pub(crate) use state::{AppState, HealthState};
 pub(crate) use store::Store;
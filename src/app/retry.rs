@@ -0,0 +1,150 @@
+use std::{future::Future, time::Duration};
+
+/// Exponential backoff policy for the operations talking to the remote services.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    /// Delay before the first retry. It is doubled after every failed attempt.
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between the attempts.
+    pub max_delay: Duration,
+    /// Number of retries after the first failed attempt.
+    pub retries: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            retries: 5,
+        }
+    }
+}
+
+impl Backoff {
+    pub(crate) fn with_retries(retries: u32) -> Self {
+        Backoff {
+            retries,
+            ..Default::default()
+        }
+    }
+
+    /// Run the operation until it succeeds, the error is not retryable or there are
+    /// no retries left.
+    ///
+    /// # Args
+    ///
+    /// - `what`: Description of the operation used in the log messages
+    /// - `is_retryable`: Decides if the operation should be retried after the error
+    /// - `op`: The operation to run
+    pub(crate) async fn retry<T, E, F, Fut>(
+        &self,
+        what: &str,
+        is_retryable: impl Fn(&E) -> bool,
+        mut op: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 0;
+
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt < self.retries && is_retryable(&err) => {
+                    attempt += 1;
+                    log::warn!(
+                        "{} failed: {}. Retrying in {:?} ({}/{})",
+                        what,
+                        err,
+                        delay,
+                        attempt,
+                        self.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max_delay);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn backoff(retries: u32) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            retries,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let calls = AtomicU32::new(0);
+
+        let res: Result<u32, String> = backoff(3)
+            .retry(
+                "test",
+                |_| true,
+                || async {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    if call < 2 {
+                        Err("boom".to_owned())
+                    } else {
+                        Ok(call)
+                    }
+                },
+            )
+            .await;
+
+        assert_eq!(res, Ok(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let calls = AtomicU32::new(0);
+
+        let res: Result<(), String> = backoff(2)
+            .retry(
+                "test",
+                |_| true,
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("boom".to_owned())
+                },
+            )
+            .await;
+
+        assert_eq!(res, Err("boom".to_owned()));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_skips_permanent_errors() {
+        let calls = AtomicU32::new(0);
+
+        let res: Result<(), String> = backoff(2)
+            .retry(
+                "test",
+                |_| false,
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("boom".to_owned())
+                },
+            )
+            .await;
+
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,78 @@
+use axum::extract::FromRef;
+
+use crate::app::{
+    backup::BackupDir,
+    health::checks::Readiness,
+    hello::{birthday::Calendar, validation::UsernamePolicy},
+    i18n::Catalog,
+    idempotency::IdempotencyPolicy,
+    Store,
+};
+use crate::setup::Logger;
+
+/// State shared with all the request handlers.
+/// The handlers extract only the parts they need, e.g. `State<Store>`.
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub store: Store,
+    pub calendar: Calendar,
+    pub catalog: Catalog,
+    pub username_policy: UsernamePolicy,
+    pub idempotency: IdempotencyPolicy,
+    pub backups: BackupDir,
+}
+
+impl FromRef<AppState> for Store {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for Calendar {
+    fn from_ref(state: &AppState) -> Self {
+        state.calendar.clone()
+    }
+}
+
+impl FromRef<AppState> for Catalog {
+    fn from_ref(state: &AppState) -> Self {
+        state.catalog.clone()
+    }
+}
+
+impl FromRef<AppState> for UsernamePolicy {
+    fn from_ref(state: &AppState) -> Self {
+        state.username_policy.clone()
+    }
+}
+
+impl FromRef<AppState> for IdempotencyPolicy {
+    fn from_ref(state: &AppState) -> Self {
+        state.idempotency.clone()
+    }
+}
+
+impl FromRef<AppState> for BackupDir {
+    fn from_ref(state: &AppState) -> Self {
+        state.backups.clone()
+    }
+}
+
+/// State of the health server, separate from the application one.
+#[derive(Clone)]
+pub(crate) struct HealthState {
+    pub logger: Logger,
+    pub readiness: Readiness,
+}
+
+impl FromRef<HealthState> for Logger {
+    fn from_ref(state: &HealthState) -> Self {
+        state.logger.clone()
+    }
+}
+
+impl FromRef<HealthState> for Readiness {
+    fn from_ref(state: &HealthState) -> Self {
+        state.readiness.clone()
+    }
+}
@@ -1,22 +1,47 @@
-use surrealdb::{engine::local::Db, Surreal};
+use std::sync::Arc;
+
+use crate::app::{
+    backup::BackupStore, hello::store::BirthdayStore, idempotency::IdempotencyStore,
+    migrations::SchemaStore,
+};
 
 #[derive(Clone)]
 pub(crate) struct Store {
-    pub db: Surreal<Db>,
+    pub birthdays: Arc<dyn BirthdayStore>,
+    pub idempotency: Arc<dyn IdempotencyStore>,
+    pub schema: Arc<dyn SchemaStore>,
+    pub backup: Arc<dyn BackupStore>,
 }
 
-/// Store is a wrapper around the database.
-/// It is used for convenience to group all database operations in one place.
+/// Store groups all the storage backends used by the application.
+/// It is passed to the axum server as a state, so the handlers don't need to know
+/// which backend was selected.
 impl Store {
-    pub(crate) fn new(db: surrealdb::Surreal<surrealdb::engine::local::Db>) -> Self {
-        Store { db }
+    pub(crate) fn new<B>(backend: B) -> Self
+    where
+        B: BirthdayStore + IdempotencyStore + SchemaStore + BackupStore + 'static,
+    {
+        let backend = Arc::new(backend);
+
+        Store {
+            birthdays: backend.clone(),
+            idempotency: backend.clone(),
+            schema: backend.clone(),
+            backup: backend,
+        }
     }
 
     #[cfg(test)]
     pub(crate) async fn new_in_mem() -> anyhow::Result<Self> {
+        use crate::app::hello::store::SurrealBirthdayStore;
+        use surrealdb::Surreal;
+
         let db = Surreal::new::<surrealdb::engine::local::Mem>(()).await?;
         db.use_ns("revolut-test").use_db("revolut").await?;
 
-        Ok(Store { db })
+        let store = Store::new(SurrealBirthdayStore::new(db));
+        crate::app::migrations::migrate(store.schema.as_ref()).await?;
+
+        Ok(store)
     }
 }
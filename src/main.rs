@@ -1,20 +1,56 @@
+use std::time::Duration;
+
 mod app;
 mod setup;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (cli, matches) = setup::config::load()?;
+
+    // Printing the configuration doesn't need any of the services.
+    if let Some(setup::Command::Config {
+        command: setup::ConfigCommand::Print,
+    }) = cli.command
+    {
+        print!("{}", setup::config::print_config(&matches)?);
+        return Ok(());
+    }
+
     // Initialize all the services required by the application.
-    let (cli, store) = setup::setup().await?;
+    let logger = setup::init_logger(&cli, &matches)?;
+    let state = setup::setup(&cli).await?;
+
+    // The commands other than `serve` operate on the storage and exit.
+    if let Some(command) = cli.command.clone() {
+        if !matches!(command, setup::Command::Serve) {
+            setup::commands::run(command, &state).await?;
+            return Ok(());
+        }
+    }
 
     // Setup the HTTP servers.
-    let (http_server, health_server) =
-        setup::http::http_server(cli.bind_addr, cli.health_bind_addr, store).await?;
+    let store = state.store.clone();
+    let readiness = setup::readiness(&cli, &state);
+    let shutdown = setup::Shutdown::new(
+        readiness.clone(),
+        Duration::from_secs(cli.shutdown_drain_seconds),
+        Duration::from_secs(cli.shutdown_timeout_seconds),
+    );
+    let (http_server, health_server) = setup::http::http_server(
+        cli.bind_addr,
+        cli.health_bind_addr,
+        state,
+        logger,
+        readiness,
+        shutdown.token(),
+        &setup::server::ServerSettings::from(&cli),
+    )
+    .await?;
 
-    // Wait for all servers to finish.
-    tokio::select! {
-        _ = http_server => log::info!("HTTP server shutdown."),
-        _ = health_server => log::info!("Health server shutdown."),
-    }
+    // Wait for the shutdown signal and stop all servers.
+    shutdown
+        .run(vec![http_server, health_server], store)
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,507 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use sha2::{Digest, Sha256};
+
+use super::with_mdc;
+use crate::app::api::ApiError;
+
+/// Header carrying the API key of the request.
+pub(crate) static X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Permission to call a group of the routes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// Read the birthdays.
+    BirthdayRead,
+    /// Create, replace and erase the birthdays.
+    BirthdayWrite,
+    /// Import, export and back up all the data.
+    Admin,
+}
+
+impl Scope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Scope::BirthdayRead => "birthday:read",
+            Scope::BirthdayWrite => "birthday:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "birthday:read" => Ok(Scope::BirthdayRead),
+            "birthday:write" => Ok(Scope::BirthdayWrite),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "Unknown scope `{}`, expected `birthday:read`, `birthday:write` or `admin`",
+                value
+            )),
+        }
+    }
+}
+
+/// API key accepted in the `X-API-Key` header. Only the digest of the key is
+/// configured, as `<name>:<SHA-256 of the key in hex>:<scopes separated by spaces>`.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiKey {
+    name: String,
+    /// Hex-encoded SHA-256 digest of the key, in lower case.
+    digest: String,
+    scopes: Vec<Scope>,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // The scopes contain colons, so they're the remainder.
+        let mut parts = value.splitn(3, ':');
+        let (Some(name), Some(digest), Some(scopes)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("The API key must be `<name>:<sha256 hex>:<scopes>`".to_owned());
+        };
+
+        if name.is_empty() {
+            return Err("The name of the API key is empty".to_owned());
+        }
+        if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "The digest of the API key `{}` must be 64 hexadecimal digits",
+                name
+            ));
+        }
+        let scopes = scopes
+            .split_whitespace()
+            .map(Scope::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ApiKey {
+            name: name.to_owned(),
+            digest: digest.to_ascii_lowercase(),
+            scopes,
+        })
+    }
+}
+
+/// Client authenticated with an API key or a bearer token, added to the extensions of
+/// its requests.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Principal {
+    /// Name of the API key or the subject of the token.
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// Credentials accepted by the public server.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AuthSettings {
+    pub api_keys: Vec<ApiKey>,
+    /// Local JWKS file with the keys verifying the bearer tokens.
+    pub jwks_file: Option<PathBuf>,
+    /// Required `iss` claim of the tokens.
+    pub jwt_issuer: Option<String>,
+    /// Required `aud` claim of the tokens.
+    pub jwt_audience: Option<String>,
+}
+
+/// Key of the JWKS file, decoded when the file is loaded.
+struct JwtKey {
+    id: Option<String>,
+    algorithm: Option<Algorithm>,
+    key: DecodingKey,
+}
+
+/// Verifies the bearer tokens signed with the keys of the JWKS file.
+struct JwtVerifier {
+    keys: Vec<JwtKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+/// Claims of the bearer tokens used by the application.
+#[derive(serde::Deserialize)]
+struct Claims {
+    sub: String,
+    /// Scopes separated by spaces, see RFC 8693.
+    #[serde(default)]
+    scope: String,
+    /// Scopes as an array, used by some of the identity providers instead of `scope`.
+    #[serde(default)]
+    scp: Vec<String>,
+}
+
+impl JwtVerifier {
+    fn load(settings: &AuthSettings, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading the JWKS file {}", path.display()))?;
+        let jwks: JwkSet = serde_json::from_str(&content)
+            .with_context(|| format!("Parsing the JWKS file {}", path.display()))?;
+        if jwks.keys.is_empty() {
+            bail!("No keys found in the JWKS file {}", path.display());
+        }
+
+        let keys = jwks
+            .keys
+            .iter()
+            .map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk).with_context(|| {
+                    format!(
+                        "Invalid key {:?} in the JWKS file {}",
+                        jwk.common.key_id,
+                        path.display()
+                    )
+                })?;
+                Ok(JwtKey {
+                    id: jwk.common.key_id.clone(),
+                    algorithm: jwk.common.algorithm,
+                    key,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(JwtVerifier {
+            keys,
+            issuer: settings.jwt_issuer.clone(),
+            audience: settings.jwt_audience.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal> {
+        let header = jsonwebtoken::decode_header(token)?;
+        // The key ID may be omitted when there is only one key.
+        let key = match &header.kid {
+            Some(kid) => self.keys.iter().find(|key| key.id.as_ref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+        .context("No key matches the token")?;
+        if key
+            .algorithm
+            .is_some_and(|algorithm| algorithm != header.alg)
+        {
+            bail!("The algorithm of the token doesn't match the key");
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.validate_nbf = true;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key.key, &validation)?.claims;
+
+        // The scopes of the other services are ignored.
+        let scopes = claims
+            .scope
+            .split_whitespace()
+            .chain(claims.scp.iter().map(String::as_str))
+            .filter_map(|scope| scope.parse().ok())
+            .collect();
+
+        Ok(Principal {
+            name: claims.sub,
+            scopes,
+        })
+    }
+}
+
+/// Authenticates the clients of the public server and checks the scopes of the routes.
+/// The routes are open to everyone when neither the API keys nor the JWKS file are
+/// configured.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    api_keys: Arc<[ApiKey]>,
+    jwt: Option<Arc<JwtVerifier>>,
+}
+
+impl Auth {
+    /// Load the keys verifying the bearer tokens.
+    pub(crate) fn load(settings: &AuthSettings) -> Result<Self> {
+        let jwt = match &settings.jwks_file {
+            Some(path) => Some(Arc::new(JwtVerifier::load(settings, path)?)),
+            None => None,
+        };
+
+        Ok(Auth {
+            api_keys: settings.api_keys.clone().into(),
+            jwt,
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt.is_some()
+    }
+
+    /// Require the scope on all the routes of the router.
+    pub(crate) fn protect<S>(&self, router: Router<S>, scope: Scope) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        if !self.is_enabled() {
+            return router;
+        }
+
+        let guard = Guard {
+            auth: self.clone(),
+            scope,
+        };
+        router.route_layer(middleware::from_fn_with_state(guard, require_scope))
+    }
+
+    /// Find the client presenting the credentials of the request.
+    /// The error is the reason the credentials were rejected, for the response.
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, &'static str> {
+        if let Some(key) = headers.get(&X_API_KEY) {
+            // Only the digests are compared, so the comparison doesn't leak the keys.
+            let digest = format!("{:x}", Sha256::digest(key.as_bytes()));
+            return self
+                .api_keys
+                .iter()
+                .find(|api_key| api_key.digest == digest)
+                .map(|api_key| Principal {
+                    name: api_key.name.clone(),
+                    scopes: api_key.scopes.clone(),
+                })
+                .ok_or("The API key is invalid");
+        }
+
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (token, &self.jwt) {
+            (Some(token), Some(jwt)) => jwt.verify(token).map_err(|err| {
+                log::info!("Rejected the bearer token: {:#}", err);
+                "The bearer token is invalid or expired"
+            }),
+            (Some(_), None) => Err("The bearer tokens are not accepted"),
+            (None, _) => Err("The request requires an API key or a bearer token"),
+        }
+    }
+
+    /// Value of the `WWW-Authenticate` header of the rejected requests.
+    fn challenge(&self) -> HeaderValue {
+        let challenge = if self.jwt.is_some() {
+            "Bearer"
+        } else {
+            "ApiKey header=\"X-API-Key\""
+        };
+        HeaderValue::from_static(challenge)
+    }
+}
+
+/// State of the `require_scope` middleware of a single router.
+#[derive(Clone)]
+struct Guard {
+    auth: Auth,
+    scope: Scope,
+}
+
+/// Tower middleware that rejects the requests without the valid credentials with
+/// `401 Unauthorized`, and the requests of the clients without the scope of the route
+/// with `403 Forbidden`.
+/// The authenticated principal is printed in the log messages of the request, the
+/// pattern is `{X(principal)}`.
+async fn require_scope(State(guard): State<Guard>, mut request: Request, next: Next) -> Response {
+    let principal = match guard.auth.authenticate(request.headers()) {
+        Ok(principal) => principal,
+        Err(message) => {
+            let error = ApiError::from_status(StatusCode::UNAUTHORIZED, message);
+            return ([(header::WWW_AUTHENTICATE, guard.auth.challenge())], error).into_response();
+        }
+    };
+    if !principal.scopes.contains(&guard.scope) {
+        log::info!(
+            "Rejected {} {} of {}, missing the {} scope",
+            request.method(),
+            request.uri().path(),
+            principal.name,
+            guard.scope
+        );
+        let message = format!("The `{}` scope is required", guard.scope);
+        return ApiError::from_status(StatusCode::FORBIDDEN, &message).into_response();
+    }
+
+    let name = principal.name.clone();
+    request.extensions_mut().insert(principal);
+
+    with_mdc("principal", name, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use jsonwebtoken::{EncodingKey, Header};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/setup/testdata/auth")
+            .join(name)
+    }
+
+    fn api_key(name: &str, key: &str, scopes: &str) -> ApiKey {
+        format!("{}:{:x}:{}", name, Sha256::digest(key), scopes)
+            .parse()
+            .unwrap()
+    }
+
+    /// Sign the token with the key of the test JWKS file, valid for `ttl` seconds.
+    fn token(claims: serde_json::Value, ttl: i64) -> String {
+        let mut claims = claims;
+        claims["exp"] = (chrono::Utc::now().timestamp() + ttl).into();
+        let header = Header {
+            kid: Some("test-key".to_owned()),
+            ..Header::new(Algorithm::ES256)
+        };
+        let key = std::fs::read(testdata("signing.key")).unwrap();
+        let key = EncodingKey::from_ec_pem(&key).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn auth() -> Auth {
+        Auth::load(&AuthSettings {
+            api_keys: vec![
+                api_key("reader", "read-key", "birthday:read"),
+                api_key("writer", "write-key", "birthday:read birthday:write"),
+            ],
+            jwks_file: Some(testdata("jwks.json")),
+            jwt_issuer: Some("https://auth.example.com".to_owned()),
+            jwt_audience: None,
+        })
+        .unwrap()
+    }
+
+    async fn call(app: &Router, header: Option<(&HeaderName, String)>) -> Response {
+        let mut request = Request::put("/").body(Body::empty()).unwrap();
+        if let Some((name, value)) = header {
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_str(&value).unwrap());
+        }
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_api_key() {
+        let key = api_key("ci", "secret", "birthday:read admin");
+        assert_eq!(key.name, "ci");
+        assert_eq!(key.scopes, vec![Scope::BirthdayRead, Scope::Admin]);
+
+        assert!("ci:abc:admin".parse::<ApiKey>().is_err());
+        assert!(format!("ci:{}", "0".repeat(64)).parse::<ApiKey>().is_err());
+        let err = format!("ci:{}:birthday:delete", "0".repeat(64))
+            .parse::<ApiKey>()
+            .unwrap_err();
+        assert!(err.contains("Unknown scope `birthday:delete`"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_require_scope() {
+        let auth = auth();
+        let app = auth.protect(
+            Router::new().route(
+                "/",
+                get(|| async {})
+                    .put(|principal: axum::Extension<Principal>| async move { principal.0.name }),
+            ),
+            Scope::BirthdayWrite,
+        );
+
+        let res = call(&app, None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let res = call(&app, Some((&X_API_KEY, "wrong-key".to_owned()))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = call(&app, Some((&X_API_KEY, "read-key".to_owned()))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = call(&app, Some((&X_API_KEY, "write-key".to_owned()))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "writer");
+
+        // Without the protection, the routes are open to everyone.
+        let open = Auth::load(&AuthSettings::default()).unwrap();
+        assert!(!open.is_enabled());
+        let app = open.protect(Router::new().route("/", get(|| async {})), Scope::Admin);
+        let res = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        let auth = auth();
+        let app = auth.protect(
+            Router::new().route("/", axum::routing::put(|| async {})),
+            Scope::BirthdayWrite,
+        );
+        let bearer = |token: String| Some((&header::AUTHORIZATION, format!("Bearer {}", token)));
+
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "iss": "https://auth.example.com",
+            "scope": "openid birthday:write",
+        });
+        let res = call(&app, bearer(token(claims.clone(), 60))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Beyond the leeway of the expiration.
+        let res = call(&app, bearer(token(claims.clone(), -120))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut other_issuer = claims.clone();
+        other_issuer["iss"] = "https://evil.example.com".into();
+        let res = call(&app, bearer(token(other_issuer, 60))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut tampered = token(claims, 60);
+        tampered.pop();
+        let res = call(&app, bearer(tampered)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let read_only = serde_json::json!({
+            "sub": "bob",
+            "iss": "https://auth.example.com",
+            "scp": ["birthday:read"],
+        });
+        let res = call(&app, bearer(token(read_only, 60))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures::TryStreamExt;
+
+use super::Command;
+use crate::app::{
+    api::{ApiError, FieldViolation},
+    audit::{AuditAction, AuditEntry},
+    backup,
+    hello::{
+        api::UserBirthdayRequest,
+        bulk,
+        store::{Birthday, ExpectedRevision, ListOrder},
+        validation::validate_birthday_request,
+    },
+    AppState,
+};
+
+/// Record printed by the `get` and `list` commands.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    username: String,
+    date_of_birth: NaiveDate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<Tz>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<u64>,
+}
+
+/// Run the command operating on the storage instead of serving the requests.
+pub(crate) async fn run(command: Command, state: &AppState) -> Result<()> {
+    match command {
+        Command::Serve => bail!("The `serve` command is run by the HTTP servers"),
+        Command::Config { .. } => bail!("The `config` command is run before the setup"),
+        // The migrations are applied during the setup, there is nothing else to do.
+        Command::Migrate => log::info!("Migrations applied."),
+        Command::Import { file, format } => import(state, &file, format.into()).await?,
+        Command::Export { file, format } => export(state, file.as_deref(), format.into()).await?,
+        Command::Get { username } => get(state, &username).await?,
+        Command::Set {
+            username,
+            date_of_birth,
+            timezone,
+            revision,
+        } => {
+            let req = UserBirthdayRequest {
+                date_of_birth,
+                timezone,
+            };
+            set(state, &username, req, revision).await?
+        }
+        Command::Delete { username } => delete(state, &username).await?,
+        Command::List { limit, after } => list(state, limit, after).await?,
+        Command::Backup { file } => {
+            let info = backup::write_backup(&state.store, &file).await?;
+            log::info!(
+                "Written the backup {} of {} bytes, SHA-256: {}",
+                info.file.display(),
+                info.size,
+                info.sha256
+            );
+        }
+        Command::Restore { file } => {
+            backup::restore(&state.store, &file).await?;
+            log::info!("Restored the backup {}", file.display());
+        }
+        Command::Stats => stats(state).await?,
+        Command::PurgeExpired => purge_expired(state).await?,
+    }
+
+    Ok(())
+}
+
+/// Describe the invalid fields in a single line.
+fn describe(violations: &[FieldViolation]) -> String {
+    let violations: Vec<_> = violations
+        .iter()
+        .map(|violation| format!("{}: {}", violation.field, violation.message))
+        .collect();
+    violations.join("; ")
+}
+
+fn invalid(err: ApiError) -> anyhow::Error {
+    if err.errors.is_empty() {
+        anyhow!(err.message)
+    } else {
+        anyhow!(describe(&err.errors))
+    }
+}
+
+/// Import the birthdays from the file. The invalid lines are logged and skipped.
+async fn import(state: &AppState, file: &Path, format: bulk::BulkFormat) -> Result<()> {
+    let input = std::fs::read_to_string(file)
+        .with_context(|| format!("Reading the imported file: {}", file.display()))?;
+
+    let report = bulk::import(
+        &state.store,
+        &state.calendar,
+        &state.username_policy,
+        format,
+        &input,
+    )
+    .await?;
+
+    for error in &report.errors {
+        log::warn!("Skipped line {}: {}", error.line, describe(&error.errors));
+    }
+    log::info!(
+        "Imported {} birthdays, {} lines failed",
+        report.imported,
+        report.failed
+    );
+
+    Ok(())
+}
+
+/// Export all the birthdays to the file, or to the standard output if not set.
+async fn export(state: &AppState, file: Option<&Path>, format: bulk::BulkFormat) -> Result<()> {
+    let output: Box<dyn Write> = match file {
+        Some(file) => Box::new(
+            File::create(file)
+                .with_context(|| format!("Creating the exported file: {}", file.display()))?,
+        ),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut output = BufWriter::new(output);
+
+    let mut chunks = std::pin::pin!(bulk::export(state.store.clone(), format));
+    while let Some(chunk) = chunks.try_next().await? {
+        output.write_all(&chunk)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+async fn get(state: &AppState, username: &str) -> Result<()> {
+    let username = state.username_policy.validate(username).map_err(invalid)?;
+    let Some(record) = state.store.birthdays.get_birthday(&username).await? else {
+        bail!("User {} not found", username);
+    };
+
+    let record = Record {
+        username,
+        date_of_birth: record.birthday.dob,
+        timezone: record.birthday.timezone,
+        revision: Some(record.revision),
+    };
+    println!("{}", serde_json::to_string(&record)?);
+
+    Ok(())
+}
+
+async fn set(
+    state: &AppState,
+    username: &str,
+    req: UserBirthdayRequest,
+    revision: Option<u64>,
+) -> Result<()> {
+    let username = state.username_policy.validate(username).map_err(invalid)?;
+    let req = validate_birthday_request(req, &state.calendar).map_err(invalid)?;
+    let birthday = Birthday {
+        dob: req.dob()?,
+        timezone: req.timezone()?,
+    };
+
+    let birthdays = &state.store.birthdays;
+    let revision = match revision {
+        None => {
+            birthdays
+                .upsert_birthday(username.clone(), birthday)
+                .await?
+        }
+        Some(revision) => {
+            let expected = ExpectedRevision::OneOf(vec![revision]);
+            birthdays
+                .update_birthday(&username, birthday, &expected)
+                .await?
+                .with_context(|| {
+                    format!(
+                        "The record of user {} doesn't exist or has a different revision",
+                        username
+                    )
+                })?
+        }
+    };
+    log::info!(
+        "Stored the birthday of user: {}, revision: {}",
+        username,
+        revision
+    );
+
+    Ok(())
+}
+
+async fn delete(state: &AppState, username: &str) -> Result<()> {
+    let username = state.username_policy.validate(username).map_err(invalid)?;
+    // There is no request, the entry has no request ID.
+    let entry = AuditEntry {
+        action: AuditAction::Erase,
+        username: username.clone(),
+        request_id: None,
+    };
+    if !state
+        .store
+        .birthdays
+        .delete_birthday(&username, entry)
+        .await?
+    {
+        bail!("User {} not found", username);
+    }
+    log::info!("Erased the data of user: {}", username);
+
+    Ok(())
+}
+
+async fn list(state: &AppState, limit: usize, after: Option<String>) -> Result<()> {
+    let order = ListOrder::Username { after };
+    let page = state.store.birthdays.list_birthdays(&order, limit).await?;
+
+    for record in page {
+        let record = Record {
+            username: record.username,
+            date_of_birth: record.birthday.dob,
+            timezone: record.birthday.timezone,
+            revision: None,
+        };
+        println!("{}", serde_json::to_string(&record)?);
+    }
+
+    Ok(())
+}
+
+async fn stats(state: &AppState) -> Result<()> {
+    let birthdays = state.store.birthdays.count_birthdays().await?;
+    let schema_version = state.store.schema.schema_version().await?;
+
+    println!("Birthdays: {}", birthdays);
+    println!("Schema version: {}", schema_version);
+
+    Ok(())
+}
+
+/// Remove the expired idempotency records. The storage isn't compacted, the disk space
+/// of the removed records is reclaimed by the database itself, e.g. by the background
+/// compaction of SpeeDb or the autovacuum of PostgreSQL.
+async fn purge_expired(state: &AppState) -> Result<()> {
+    let purged = state.store.idempotency.purge_expired(Utc::now()).await?;
+    log::info!("Removed {} expired idempotency records", purged);
+
+    Ok(())
+}
@@ -1,15 +1,170 @@
-use crate::app::Store;
-use anyhow::Result;
-use surrealdb::{engine::local::SpeeDb, Surreal};
+use crate::app::{
+    hello::store::{
+        is_connection_error, InMemoryBirthdayStore, PostgresBirthdayStore, SurrealBirthdayStore,
+    },
+    retry::Backoff,
+    Store,
+};
+use anyhow::{anyhow, Context, Result};
+use surrealdb::{
+    engine::{
+        any::{self, Any},
+        local::SpeeDb,
+    },
+    opt::auth::Database,
+    Surreal,
+};
 
-use super::Cli;
+use super::{cli::Storage, Cli};
 
-/// Initialize the database.
+/// Initialize the storage backend selected with the `--storage` option.
 pub(super) async fn init_db(cli: &Cli) -> Result<Store> {
-    let db = Surreal::new::<SpeeDb>(cli.data_dir.clone()).await?;
-    db.use_ns("revolut").use_db("revolut").await?;
+    let store = match cli.storage {
+        Storage::Embedded => {
+            let db = Surreal::new::<SpeeDb>(cli.data_dir.clone())
+                .await
+                .map_err(|err| {
+                    if is_locked(&err.to_string()) {
+                        anyhow!(
+                            "The data directory {} is used by another process, e.g. the running server. \
+                             Use the API while the server is running",
+                            cli.data_dir.display()
+                        )
+                    } else {
+                        err.into()
+                    }
+                })?;
+            db.use_ns("revolut").use_db("revolut").await?;
 
-    let store = Store::new(db);
+            Store::new(SurrealBirthdayStore::new(db))
+        }
+        Storage::Remote => {
+            let backoff = Backoff::with_retries(cli.surreal_retries);
+            let db = connect_remote(&RemoteConfig::from(cli), &backoff).await?;
+
+            Store::new(SurrealBirthdayStore::new(db).with_backoff(backoff))
+        }
+        Storage::Postgres => {
+            let url = cli
+                .postgres_url
+                .as_deref()
+                .context("The `--postgres-url` option is required for the postgres storage")?;
+
+            Store::new(PostgresBirthdayStore::connect(url).await?)
+        }
+        Storage::Memory => {
+            log::warn!("Using the in-memory storage. The data will be lost on shutdown.");
+            Store::new(InMemoryBirthdayStore::default())
+        }
+    };
 
     Ok(store)
 }
+
+/// Check if opening the embedded storage failed because another process holds the lock
+/// of the data directory. SpeeDb locks the `LOCK` file of the directory while it's open.
+fn is_locked(error: &str) -> bool {
+    error.contains("lock file") && error.contains("LOCK")
+}
+
+/// Connection settings of the remote SurrealDB server.
+struct RemoteConfig<'a> {
+    endpoint: &'a str,
+    namespace: &'a str,
+    database: &'a str,
+    credentials: Option<(&'a str, &'a str)>,
+}
+
+impl<'a> From<&'a Cli> for RemoteConfig<'a> {
+    fn from(cli: &'a Cli) -> Self {
+        RemoteConfig {
+            endpoint: cli.surreal_endpoint.as_deref().unwrap_or_default(),
+            namespace: &cli.surreal_namespace,
+            database: &cli.surreal_database,
+            credentials: cli
+                .surreal_username
+                .as_deref()
+                .zip(cli.surreal_password.as_deref()),
+        }
+    }
+}
+
+/// Connect to the remote SurrealDB server, retrying with the backoff until the
+/// server becomes available.
+///
+/// Once connected, the WebSocket client re-establishes the lost connections on its
+/// own and replays the sign in and the selected namespace and database.
+async fn connect_remote(config: &RemoteConfig<'_>, backoff: &Backoff) -> Result<Surreal<Any>> {
+    log::info!("Connecting to SurrealDB at {}", config.endpoint);
+
+    let db = backoff
+        .retry("Connecting to SurrealDB", is_connection_error, || async {
+            let db = any::connect(config.endpoint).await?;
+
+            if let Some((username, password)) = config.credentials {
+                db.signin(Database {
+                    namespace: config.namespace,
+                    database: config.database,
+                    username,
+                    password,
+                })
+                .await?;
+            }
+
+            db.use_ns(config.namespace).use_db(config.database).await?;
+
+            Ok(db)
+        })
+        .await
+        .with_context(|| format!("Connecting to SurrealDB at {}", config.endpoint))?;
+
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::hello::store::BirthdayStore;
+
+    use super::*;
+
+    #[test]
+    fn test_is_locked() {
+        assert!(is_locked(
+            "IO error: While lock file: /var/lib/revolut/LOCK: Resource temporarily unavailable"
+        ));
+        assert!(!is_locked(
+            "IO error: No such file or directory: While opening a file for sequentially reading: /var/lib/revolut/CURRENT"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_remote_with_in_memory_engine() {
+        let config = RemoteConfig {
+            endpoint: "mem://",
+            namespace: "revolut-test",
+            database: "revolut",
+            credentials: None,
+        };
+
+        let db = connect_remote(&config, &Backoff::with_retries(0))
+            .await
+            .unwrap();
+        let store = SurrealBirthdayStore::new(db);
+
+        assert!(store.get_birthday("foo").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect_remote_with_unreachable_server() {
+        let config = RemoteConfig {
+            endpoint: "http://127.0.0.1:1",
+            namespace: "revolut-test",
+            database: "revolut",
+            credentials: None,
+        };
+
+        let res = connect_remote(&config, &Backoff::with_retries(0)).await;
+
+        assert!(res.is_err());
+    }
+}
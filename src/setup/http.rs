@@ -1,28 +1,35 @@
 use anyhow::{Context, Result};
 use axum::{
-    extract::Request,
-    http::HeaderName,
+    extract::{DefaultBodyLimit, Request},
     middleware::{self, Next},
     response::Response,
-    routing::{get, put},
+    routing::{get, post, put},
     Router,
 };
 use rand::Rng;
-use std::{fmt::Display, time::Duration};
+use std::fmt::Display;
 use tokio::signal;
 use tokio::{net::ToSocketAddrs, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::{
     request_id::{MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
-    timeout::TimeoutLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
     ServiceBuilderExt,
 };
 
-use crate::app::{health, hello, Store};
-
-/// The header name for the request ID used for tracing.
-static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+use super::{
+    auth::{Auth, Scope},
+    server::{self, ConnectionSettings, RequestLimits, ServerSettings},
+    tls::{self, Tls, TlsFiles},
+    with_mdc, Logger,
+};
+use crate::app::{
+    api::{self, X_REQUEST_ID},
+    backup,
+    health::{self, checks::Readiness, checks::ServerCheck},
+    hello, idempotency, AppState, HealthState,
+};
 
 /// Create HTTP servers for serving external requests as well as the health requests.
 /// The servers are split so the health port doesn't get exposed to the external users.
@@ -31,8 +38,15 @@ static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
 ///
 /// - `bind_addr`: The address to bind the external HTTP server to
 /// - `health_bind_addr`: The address to bind the health server to
-/// - `db`: The storage object that will be passed to the axum server and can be
-///         later accessed in the request handlers
+/// - `state`: The application state that will be passed to the axum server and can be
+///   later accessed in the request handlers
+/// - `logger`: The logger reconfigured through the admin endpoints of the health server
+///   and on `SIGHUP`
+/// - `readiness`: The checks of the dependencies reported by the health server, the
+///   check of the main server is added here
+/// - `shutdown`: The token stopping the servers, see `Shutdown`
+/// - `settings`: The limits of the requests, the connection settings and the TLS files
+///   of each server, along with the credentials accepted by the external server
 ///
 /// # Returns
 ///
@@ -41,13 +55,115 @@ static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
 pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
     bind_addr: A,
     health_bind_addr: A,
-    db: Store,
+    state: AppState,
+    logger: Logger,
+    readiness: Readiness,
+    shutdown: CancellationToken,
+    settings: &ServerSettings,
 ) -> Result<(JoinHandle<()>, JoinHandle<()>)> {
-    let app = Router::new()
+    let auth = Auth::load(&settings.auth)?;
+    if !auth.is_enabled() {
+        log::warn!("Neither API keys nor a JWKS file are configured, the API is open to everyone");
+    }
+
+    let app = public_router(state, &auth, &settings.public);
+
+    log::info!("Listening http server on {}", &bind_addr);
+    let server_handle = create_server(
+        bind_addr,
+        app,
+        settings.public_connections.clone(),
+        settings.public_tls.clone(),
+        shutdown.clone(),
+    )
+    .await?;
+    let (server_check, server_handle) = ServerCheck::watch(server_handle);
+    let readiness = readiness.with_check(server_check);
+
+    tokio::spawn(reload_on_hangup(logger.clone()));
+
+    let admin = Router::new()
         .route(
-            "/hello/:username",
-            put(hello::api::upsert_user).get(hello::api::get_birthday),
+            "/admin/log-level",
+            get(health::api::get_log_level).put(health::api::set_log_level),
         )
+        .route("/admin/reload", post(health::api::reload_config));
+    // With the client CA, only the clients with its certificates may use the admin
+    // endpoints (mTLS).
+    let admin = match &settings.health_tls {
+        Some(TlsFiles {
+            client_ca: Some(_), ..
+        }) => admin.route_layer(middleware::from_fn(tls::require_client_certificate)),
+        _ => admin,
+    };
+
+    let health_app = Router::new()
+        .route("/metrics", get(health::api::metrics))
+        // Kept for the probes configured before `/livez` was added.
+        .route("/health", get(health::api::health))
+        .route("/livez", get(health::api::health))
+        .route("/readyz", get(health::api::readyz))
+        .merge(admin)
+        .fallback(api::route_not_found)
+        .with_state(HealthState { logger, readiness });
+
+    log::info!("Listening health server on {}", &health_bind_addr);
+    let health_app = settings.health.apply(health_app);
+    let health_handle = create_server(
+        health_bind_addr,
+        health_app,
+        settings.health_connections.clone(),
+        settings.health_tls.clone(),
+        shutdown,
+    )
+    .await?;
+
+    Ok((server_handle, health_handle))
+}
+
+/// Create the router of the external HTTP server, with the credentials checked per
+/// route and the request limits applied.
+fn public_router(state: AppState, auth: &Auth, limits: &RequestLimits) -> Router {
+    let reads = Router::new()
+        .route("/hello", get(hello::api::list_users))
+        .route("/birthdays/upcoming", get(hello::api::upcoming_birthdays))
+        .route("/hello/:username", get(hello::api::get_birthday));
+    let writes = Router::new().route(
+        "/hello/:username",
+        // Only the writes are replayed.
+        put(hello::api::upsert_user)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                idempotency::idempotency,
+            ))
+            .delete(hello::api::delete_user),
+    );
+    let admin = Router::new()
+        .route("/admin/backups", post(backup::create_backup))
+        .route(
+            "/admin/:operation",
+            post(hello::bulk::import_birthdays)
+                .layer(DefaultBodyLimit::max(hello::bulk::MAX_IMPORT_SIZE))
+                .get(hello::bulk::export_birthdays)
+                // Match the operation before the body is read.
+                .layer(middleware::from_fn(hello::bulk::route_operation)),
+        );
+
+    // The methods of the same path are merged, each keeping the scope of its router.
+    let app = Router::new()
+        .merge(auth.protect(reads, Scope::BirthdayRead))
+        .merge(auth.protect(writes, Scope::BirthdayWrite));
+    // The backups, the import and the export read the whole dataset or write to the disk
+    // of the server, so they are never open to everyone.
+    let app = if auth.is_enabled() {
+        app.merge(auth.protect(admin, Scope::Admin))
+    } else {
+        log::warn!("The admin endpoints are disabled, as the API isn't authenticated");
+        app
+    };
+
+    let app = app
+        .fallback(api::route_not_found)
         .layer(
             ServiceBuilder::new()
                 .set_x_request_id(RandomRequestId::default())
@@ -57,6 +173,8 @@ pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
                 ))
                 // Inject the request ID into the MDC.
                 .layer(middleware::from_fn(mdc_injector))
+                // Point the error responses to the request ID.
+                .layer(middleware::from_fn(api::problem_instance))
                 // propagate `x-request-id` headers from request to response
                 .layer(PropagateRequestIdLayer::new(X_REQUEST_ID.clone()))
                 .layer(
@@ -67,19 +185,9 @@ pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
                 .layer(middleware::from_fn(health::middleware::metrics))
                 .propagate_x_request_id(),
         )
-        .with_state(db);
-
-    let health_app = Router::new()
-        .route("/metrics", get(health::api::metrics))
-        .route("/health", get(health::api::health));
+        .with_state(state);
 
-    log::info!("Listening http server on {}", &bind_addr);
-    let server_handle = create_server(bind_addr, app).await?;
-
-    log::info!("Listening health server on {}", &health_bind_addr);
-    let health_handle = create_server(health_bind_addr, health_app).await?;
-
-    Ok((server_handle, health_handle))
+    limits.apply(app)
 }
 
 /// Create a new HTTP server.
@@ -87,7 +195,12 @@ pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
 /// # Args
 ///
 /// - `bind_addr`: The address to bind the server to
-/// - `app`: The application router
+/// - `app`: The application router, with the request limits applied
+/// - `connections`: The settings of the HTTP connections
+/// - `tls`: The TLS files of the server, reloaded when they change. The plain HTTP is
+///   served if not set
+/// - `shutdown`: The token stopping the server gracefully, cancelled by the server
+///   when it stops by itself
 ///
 /// # Returns
 ///
@@ -102,8 +215,13 @@ pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
 /// let health = Router::new()
 ///   .route("/health", get(health::api::health));
 ///
-/// let metrics_handle = create_server("[::1]:4200", metrics).await?;
-/// let health_handle = create_server("[::1]:4300", health).await?;
+/// let shutdown = CancellationToken::new();
+/// let metrics_handle = create_server(
+///   "[::1]:4200", metrics, ConnectionSettings::public(&cli), None, shutdown.clone(),
+/// ).await?;
+/// let health_handle = create_server(
+///   "[::1]:4300", health, ConnectionSettings::health(&cli), None, shutdown,
+/// ).await?;
 ///
 /// tokio::select! {
 ///   _ = metrics_handle => log::info!("HTTP server shutdown."),
@@ -113,64 +231,62 @@ pub(crate) async fn http_server<A: ToSocketAddrs + Display>(
 async fn create_server<A: ToSocketAddrs + Display>(
     bind_addr: A,
     app: Router,
+    connections: ConnectionSettings,
+    tls: Option<TlsFiles>,
+    shutdown: CancellationToken,
 ) -> Result<JoinHandle<()>> {
-    // Add a timeout layer to let the application close the connections gracefully.
-    let app = app.layer(TimeoutLayer::new(Duration::from_secs(10)));
+    let tls = match tls {
+        Some(files) => {
+            let tls = Tls::load(files, connections.http2)?;
+            tokio::spawn(tls.clone().watch(shutdown.clone()));
+            Some(tls)
+        }
+        None => None,
+    };
 
     let listener = tokio::net::TcpListener::bind(bind_addr)
         .await
         .context("Creating the http server listener")?;
 
-    let server_handle = tokio::spawn(async {
-        if let Err(err) = axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-        {
-            log::error!("Error serving HTTP: {}", err);
-        }
+    let server_handle = tokio::spawn(async move {
+        server::serve(listener, app, &connections, tls, shutdown.clone()).await;
+        // Stop the other servers as well, e.g. when this one failed.
+        shutdown.cancel();
     });
     Ok(server_handle)
 }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
-
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
+/// Reload the configuration each time the process receives `SIGHUP`.
+#[cfg(unix)]
+async fn reload_on_hangup(logger: Logger) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::error!("Failed to install the SIGHUP handler: {}", err);
+            return;
+        }
     };
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
-
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading the configuration");
+        if let Err(err) = logger.reload() {
+            log::error!("Failed to reload the configuration: {:#}", err);
+        }
     }
 }
 
+#[cfg(not(unix))]
+async fn reload_on_hangup(_logger: Logger) {}
+
 /// Tower middleware that injects the request ID into the MDC.
 /// The request ID will be printed in all log messages for the duration of the request.
-/// The pattern for printing the request ID in logs is `{X(request_id)}`
+/// The pattern for printing the request ID in logs is `{X(request_id)}`.
+/// The principal is added the same way by the authentication, see `Auth::protect`.
 pub async fn mdc_injector(request: Request, next: Next) -> Response {
     let request_id = request.extensions().get::<RequestId>().unwrap();
     let request_id = format!("{:?}", request_id);
-    log_mdc::insert("request_id", request_id);
-
-    // Call the next middleware in the chain.
-    let response = next.run(request).await;
-
-    // Clear the MDC after the response is generated.
-    log_mdc::clear();
 
-    response
+    with_mdc("request_id", request_id, next.run(request)).await
 }
 
 // A `MakeRequestId` that increments an atomic counter
@@ -185,3 +301,121 @@ impl MakeRequestId for RandomRequestId {
         Some(RequestId::new(request_id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+    };
+    use sha2::{Digest, Sha256};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::app::{
+        api::{ApiError, ErrorCode},
+        backup::BackupDir,
+        clock::SystemClock,
+        hello::{
+            birthday::{Calendar, LeapDayPolicy},
+            validation::{CharClass, UsernamePolicy},
+        },
+        i18n::Catalog,
+        idempotency::IdempotencyPolicy,
+        Store,
+    };
+    use crate::setup::auth::AuthSettings;
+
+    async fn app(auth: AuthSettings) -> Router {
+        let state = AppState {
+            store: Store::new_in_mem().await.unwrap(),
+            calendar: Calendar::new(SystemClock, LeapDayPolicy::Feb28, chrono_tz::Tz::UTC),
+            catalog: Catalog::new("en".parse().unwrap()).unwrap(),
+            username_policy: UsernamePolicy::new(&[CharClass::Letters], 1, 64, true, false)
+                .unwrap(),
+            idempotency: IdempotencyPolicy::new(SystemClock, chrono::Duration::hours(24)),
+            backups: BackupDir::new(PathBuf::from("backups"), 7, SystemClock),
+        };
+        let auth = Auth::load(&auth).unwrap();
+        let limits = RequestLimits {
+            timeout: Duration::from_secs(10),
+            max_body_size: 1024,
+            max_concurrent_requests: 10,
+        };
+
+        public_router(state, &auth, &limits)
+    }
+
+    /// Settings with the `reader` API key `read-key`, allowed to read the birthdays.
+    fn reader() -> AuthSettings {
+        let key = format!("reader:{:x}:birthday:read", Sha256::digest("read-key"));
+        AuthSettings {
+            api_keys: vec![key.parse().unwrap()],
+            ..AuthSettings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_require_authentication() {
+        let requests = || {
+            [
+                Request::get("/admin/birthdays:export")
+                    .body(Body::empty())
+                    .unwrap(),
+                Request::post("/admin/backups").body(Body::empty()).unwrap(),
+            ]
+        };
+
+        for req in requests() {
+            let res = app(AuthSettings::default())
+                .await
+                .oneshot(req)
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        for req in requests() {
+            let res = app(reader()).await.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_request() {
+        let res = app(reader())
+            .await
+            .oneshot(Request::get("/hello/foo").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers()[header::WWW_AUTHENTICATE],
+            "ApiKey header=\"X-API-Key\""
+        );
+        let request_id = res.headers()[&X_REQUEST_ID].to_str().unwrap().to_owned();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let error: ApiError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, ErrorCode::Unauthorized);
+        assert_eq!(error.instance, Some(request_id));
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_request() {
+        let res = app(reader())
+            .await
+            .oneshot(
+                Request::get("/hello/foo")
+                    .header("x-api-key", "read-key")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(!res.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+}
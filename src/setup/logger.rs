@@ -1,34 +1,151 @@
+use std::{future::Future, sync::Arc};
+
 use anyhow::Context;
+use clap::ArgMatches;
+use log::LevelFilter;
 use log4rs::{
-    append::console::ConsoleAppender,
+    append::console::{ConsoleAppender, Target},
     config::{Appender, Root},
     encode::json::JsonEncoder,
-    Config,
+    Config, Handle,
 };
 
-use super::Cli;
+use super::{cli::LogEncoder, config, Cli, Command};
+
+/// Handle to the logger of the running application, used to change the log level
+/// without a restart.
+#[derive(Clone, Debug)]
+pub(crate) struct Logger {
+    handle: Handle,
+    encoder: LogEncoder,
+    target: Target,
+    /// Configuration the application was started with, the reloaded configuration is
+    /// compared with it.
+    startup: Arc<ArgMatches>,
+}
+
+impl Logger {
+    /// The current log level.
+    pub(crate) fn level(&self) -> LevelFilter {
+        log::max_level()
+    }
+
+    /// Swap the logger configuration for the one with the new level.
+    pub(crate) fn set_level(&self, level: LevelFilter) -> anyhow::Result<()> {
+        let config = logger_config(&self.encoder, self.target, level)?;
+        self.handle.set_config(config);
+        log::info!("Log level set to {}", level);
+
+        Ok(())
+    }
+
+    /// Load the configuration again, e.g. after the configuration file has changed,
+    /// and apply its log level. The other settings require a restart, so their changes
+    /// are only reported in the log.
+    ///
+    /// Returns the new log level.
+    pub(crate) fn reload(&self) -> anyhow::Result<LevelFilter> {
+        let (cli, matches) = config::reload()?;
+        let level = cli.log_level.into();
+        self.set_level(level)?;
+
+        let ignored: Vec<_> = config::changed_options(&self.startup, &matches)
+            .into_iter()
+            .filter(|option| option != "log-level")
+            .collect();
+        if !ignored.is_empty() {
+            log::warn!(
+                "Only the log level was reloaded, the changes of {} require a restart",
+                ignored.join(", ")
+            );
+        }
+
+        Ok(level)
+    }
+}
+
+/// Initializes the logger based on the CLI configuration, along with the matches it
+/// was parsed from.
+pub(crate) fn init_logger(cli: &Cli, matches: &ArgMatches) -> anyhow::Result<Logger> {
+    // Keep the standard output for the results of the command.
+    let target = if cli.command.as_ref().is_some_and(Command::writes_stdout) {
+        Target::Stderr
+    } else {
+        Target::Stdout
+    };
+
+    let config = logger_config(&cli.log_encoder, target, cli.log_level.clone().into())?;
+    let handle = log4rs::init_config(config).context("Initializing logger")?;
+
+    Ok(Logger {
+        handle,
+        encoder: cli.log_encoder.clone(),
+        target,
+        startup: Arc::new(matches.clone()),
+    })
+}
 
-/// Initializes the logger based on the CLI configuration.
-pub(crate) fn init_logger(cli: &Cli) -> anyhow::Result<()> {
-    let stdout_builder = ConsoleAppender::builder();
+fn logger_config(
+    encoder: &LogEncoder,
+    target: Target,
+    log_level: LevelFilter,
+) -> anyhow::Result<Config> {
+    let stdout_builder = ConsoleAppender::builder().target(target);
 
-    let stdout = match &cli.log_encoder {
-        super::cli::LogEncoder::Json => stdout_builder.encoder(Box::new(JsonEncoder::new())),
-        super::cli::LogEncoder::Text => {
+    let stdout = match encoder {
+        LogEncoder::Json => stdout_builder.encoder(Box::new(JsonEncoder::new())),
+        LogEncoder::Text => {
             stdout_builder.encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
-                "{d} {h({l})} {t}: {m} {X(request_id)}{n}",
+                "{d} {h({l})} {t}: {m} {X(request_id)} {X(principal)}{n}",
             )))
         }
     }
     .build();
 
-    let log_level = cli.log_level.clone().into();
-
-    let config = Config::builder()
+    Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
         .build(Root::builder().appender("stdout").build(log_level))
-        .context("Configuring logger")?;
+        .context("Configuring logger")
+}
+
+/// Run the future with the value in the MDC, e.g. the request ID of the request it
+/// handles. The MDC is thread local, while the future may be resumed on any thread
+/// of the runtime, interleaved with the futures of the other requests. So the value
+/// is inserted each time the future is polled, and removed when it yields.
+pub(crate) async fn with_mdc<F: Future>(key: &'static str, value: String, future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        log_mdc::insert(key, value.as_str());
+        let poll = future.as_mut().poll(cx);
+        log_mdc::remove(key);
+        poll
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_id() -> Option<String> {
+        log_mdc::get("request_id", |id| id.map(str::to_owned))
+    }
 
-    log4rs::init_config(config).context("Initializing logger")?;
-    Ok(())
+    #[tokio::test]
+    async fn test_with_mdc() {
+        let request = |id: &str| {
+            with_mdc("request_id", id.to_owned(), async {
+                let before = request_id();
+                tokio::task::yield_now().await;
+                (before, request_id())
+            })
+        };
+
+        // Both futures are interleaved on the same thread.
+        let (first, second) = tokio::join!(request("1"), request("2"));
+
+        assert_eq!(first, (Some("1".to_owned()), Some("1".to_owned())));
+        assert_eq!(second, (Some("2".to_owned()), Some("2".to_owned())));
+        assert_eq!(request_id(), None);
+    }
 }
@@ -1,4 +1,4 @@
-use prometheus::{register_counter_vec, CounterVec, HistogramVec};
+use prometheus::{register_counter, register_counter_vec, Counter, CounterVec, HistogramVec};
 
 use lazy_static::lazy_static;
 use prometheus::{opts, register_histogram_vec};
@@ -15,4 +15,9 @@ lazy_static! {
         &["endpoint", "method"]
     )
     .unwrap();
+    pub static ref HTTP_SHED_COUNTER: Counter = register_counter!(opts!(
+        "http_requests_shed_total",
+        "Number of HTTP requests rejected over the concurrency limit.",
+    ))
+    .unwrap();
 }
@@ -1,21 +1,76 @@
+pub(crate) mod auth;
 mod cli;
+pub(crate) mod commands;
+pub(crate) mod config;
 mod db;
 pub(crate) mod http;
 mod logger;
 pub mod metrics;
+pub(crate) mod server;
+mod shutdown;
+pub(crate) mod tls;
 
-use clap::Parser;
-pub(crate) use cli::Cli;
-pub(crate) use logger::init_logger;
+pub(crate) use cli::{Cli, Command, ConfigCommand};
+pub(crate) use logger::{init_logger, with_mdc, Logger};
+pub(crate) use shutdown::Shutdown;
 
-use crate::app::Store;
+use crate::app::{
+    backup::BackupDir,
+    clock::SystemClock,
+    health::checks::{DiskCheck, Readiness, StoreCheck},
+    hello::{birthday::Calendar, validation::UsernamePolicy},
+    i18n::Catalog,
+    idempotency::IdempotencyPolicy,
+    migrations, AppState,
+};
 
-/// Initialize the application services.
-pub(super) async fn setup() -> anyhow::Result<(Cli, Store)> {
-    let cli = Cli::parse();
-    init_logger(&cli)?;
+/// Initialize the application services. The logger must be initialized before.
+pub(super) async fn setup(cli: &Cli) -> anyhow::Result<AppState> {
+    let catalog = Catalog::new(cli.fallback_locale.clone())?;
+    let username_policy = UsernamePolicy::new(
+        &cli.username_chars,
+        cli.username_min_length,
+        cli.username_max_length,
+        !cli.no_username_normalization,
+        cli.username_case_folding,
+    )?;
 
-    let db = db::init_db(&cli).await?;
+    let db = db::init_db(cli).await?;
 
-    Ok((cli, db))
+    // Refuse to start with the schema this binary doesn't know about. The restored
+    // database is migrated after the backup is loaded, as it must be empty before.
+    if !matches!(cli.command, Some(Command::Restore { .. })) {
+        migrations::migrate(db.schema.as_ref()).await?;
+    }
+
+    let calendar = Calendar::new(SystemClock, cli.leap_day_birthday, cli.default_timezone);
+    let idempotency = IdempotencyPolicy::new(
+        SystemClock,
+        chrono::Duration::hours(cli.idempotency_ttl_hours.into()),
+    );
+
+    Ok(AppState {
+        store: db,
+        calendar,
+        catalog,
+        username_policy,
+        idempotency,
+        backups: BackupDir::new(cli.backup_dir.clone(), cli.backup_keep, SystemClock),
+    })
+}
+
+/// Register the checks of the dependencies deciding whether the application is ready
+/// to serve the requests. The check of the main server is added when it's started.
+pub(super) fn readiness(cli: &Cli, state: &AppState) -> Readiness {
+    let timeout = std::time::Duration::from_millis(cli.health_check_timeout_ms);
+    let readiness = Readiness::new(timeout).with_check(StoreCheck(state.store.clone()));
+
+    // Only the embedded storage keeps the data on the local disk.
+    match cli.storage {
+        cli::Storage::Embedded => readiness.with_check(DiskCheck {
+            dir: cli.data_dir.clone(),
+            min_free: cli.min_free_disk_mb * 1024 * 1024,
+        }),
+        _ => readiness,
+    }
 }
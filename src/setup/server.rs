@@ -0,0 +1,506 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{DefaultBodyLimit, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Extension, Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::Semaphore,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower_http::timeout::TimeoutLayer;
+
+use super::{
+    auth::AuthSettings,
+    metrics,
+    tls::{ClientCertificate, Tls, TlsFiles},
+    Cli,
+};
+use crate::app::api::{self, ApiError};
+
+/// Number of seconds the clients are asked to wait before retrying the shed requests.
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Time limit of the TLS handshake of the new connections.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits of the requests served by one of the routers.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestLimits {
+    pub timeout: Duration,
+    /// Default maximal size of the request body in bytes. The routes may set their own.
+    pub max_body_size: usize,
+    /// Requests over the limit are rejected right away instead of being queued.
+    pub max_concurrent_requests: usize,
+}
+
+impl RequestLimits {
+    /// Limits of the public router.
+    pub(crate) fn public(cli: &Cli) -> Self {
+        RequestLimits {
+            timeout: Duration::from_millis(cli.request_timeout_ms),
+            max_body_size: cli.max_body_bytes,
+            max_concurrent_requests: cli.max_concurrent_requests,
+        }
+    }
+
+    /// Limits of the health router.
+    pub(crate) fn health(cli: &Cli) -> Self {
+        RequestLimits {
+            timeout: Duration::from_millis(cli.health_request_timeout_ms),
+            max_body_size: cli.health_max_body_bytes,
+            max_concurrent_requests: cli.health_max_concurrent_requests,
+        }
+    }
+
+    /// Apply the limits to all the routes of the router.
+    /// The errors of all the layers are mapped to the problem documents.
+    pub(crate) fn apply(&self, router: Router) -> Router {
+        let permits = Arc::new(Semaphore::new(self.max_concurrent_requests));
+
+        router
+            .layer(DefaultBodyLimit::max(self.max_body_size))
+            // Let the application close the connections gracefully.
+            .layer(TimeoutLayer::new(self.timeout))
+            .layer(middleware::from_fn_with_state(permits, shed_load))
+            .layer(middleware::from_fn(api::problem_responses))
+    }
+}
+
+/// Tower middleware that rejects the requests over the concurrency limit with
+/// `503 Service Unavailable`, so the overloaded server doesn't queue them until they
+/// time out.
+async fn shed_load(
+    State(permits): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(_permit) = permits.try_acquire() else {
+        metrics::HTTP_SHED_COUNTER.inc();
+        let error = ApiError::from_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server is overloaded. Please retry later.",
+        );
+        return (
+            [(header::RETRY_AFTER, RETRY_AFTER_SECONDS.to_string())],
+            error,
+        )
+            .into_response();
+    };
+
+    next.run(request).await
+}
+
+/// Settings of the HTTP connections to a server.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionSettings {
+    /// Keep the HTTP/1 connections open for the next requests.
+    pub keep_alive: bool,
+    /// Accept the HTTP/2 connections along with the HTTP/1 ones.
+    pub http2: bool,
+    /// Size of the HTTP/1 read buffer, which limits the request line and the headers.
+    pub http1_max_buf_size: usize,
+    /// Size of the headers of the HTTP/2 requests.
+    pub http2_max_header_list_size: u32,
+    pub http2_max_concurrent_streams: u32,
+    /// Interval of the HTTP/2 pings keeping the idle connections alive.
+    pub http2_keep_alive_interval: Option<Duration>,
+}
+
+impl ConnectionSettings {
+    /// Settings of the connections to the public server.
+    pub(crate) fn public(cli: &Cli) -> Self {
+        ConnectionSettings {
+            keep_alive: !cli.no_keep_alive,
+            http2: !cli.no_http2,
+            http1_max_buf_size: cli.http1_max_buf_bytes,
+            http2_max_header_list_size: cli.http2_max_header_list_bytes,
+            http2_max_concurrent_streams: cli.http2_max_concurrent_streams,
+            http2_keep_alive_interval: cli
+                .http2_keep_alive_interval_seconds
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Settings of the connections to the health server.
+    pub(crate) fn health(cli: &Cli) -> Self {
+        ConnectionSettings {
+            keep_alive: !cli.health_no_keep_alive,
+            http2: !cli.health_no_http2,
+            http1_max_buf_size: cli.health_http1_max_buf_bytes,
+            http2_max_header_list_size: cli.health_http2_max_header_list_bytes,
+            http2_max_concurrent_streams: cli.health_http2_max_concurrent_streams,
+            http2_keep_alive_interval: cli
+                .health_http2_keep_alive_interval_seconds
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+/// Settings of both servers.
+#[derive(Clone, Debug)]
+pub(crate) struct ServerSettings {
+    pub public: RequestLimits,
+    pub health: RequestLimits,
+    pub public_connections: ConnectionSettings,
+    pub health_connections: ConnectionSettings,
+    /// TLS files of the public server, the plain HTTP is served if not set.
+    pub public_tls: Option<TlsFiles>,
+    /// TLS files of the health server, along with the CA of the admin clients.
+    pub health_tls: Option<TlsFiles>,
+    /// Credentials accepted by the public server.
+    pub auth: AuthSettings,
+}
+
+impl From<&Cli> for ServerSettings {
+    fn from(cli: &Cli) -> Self {
+        let public_tls = match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: None,
+            }),
+            _ => None,
+        };
+        let health_tls = public_tls.clone().map(|files| TlsFiles {
+            client_ca: cli.health_tls_client_ca.clone(),
+            ..files
+        });
+
+        ServerSettings {
+            public: RequestLimits::public(cli),
+            health: RequestLimits::health(cli),
+            public_connections: ConnectionSettings::public(cli),
+            health_connections: ConnectionSettings::health(cli),
+            public_tls,
+            health_tls,
+            auth: AuthSettings {
+                api_keys: cli.api_keys.clone(),
+                jwks_file: cli.jwks_file.clone(),
+                jwt_issuer: cli.jwt_issuer.clone(),
+                jwt_audience: cli.jwt_audience.clone(),
+            },
+        }
+    }
+}
+
+/// Serve the connections accepted by the listener until the shutdown token is
+/// cancelled, then wait for the open connections to complete their requests.
+/// The connections are encrypted if the TLS configuration is set.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    app: Router,
+    settings: &ConnectionSettings,
+    tls: Option<Tls>,
+    shutdown: CancellationToken,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .keep_alive(settings.keep_alive)
+        .max_buf_size(settings.http1_max_buf_size);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_header_list_size(settings.http2_max_header_list_size)
+        .max_concurrent_streams(settings.http2_max_concurrent_streams)
+        .keep_alive_interval(settings.http2_keep_alive_interval);
+    let builder = if settings.http2 {
+        builder
+    } else {
+        builder.http1_only()
+    };
+
+    let connections = TaskTracker::new();
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // E.g. too many open files, wait for some of the connections to close.
+                    log::error!("Failed to accept the connection: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        let builder = builder.clone();
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        // The handshake is done in the task, so the slow clients don't block the others.
+        let acceptor = tls.as_ref().map(Tls::acceptor);
+        connections.spawn(async move {
+            let result = match acceptor {
+                None => serve_connection(&builder, stream, app, &shutdown).await,
+                Some(acceptor) => {
+                    let stream = match tokio::time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        acceptor.accept(stream),
+                    )
+                    .await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(err)) => {
+                            log::debug!("TLS handshake with {} failed: {}", remote_addr, err);
+                            return;
+                        }
+                        Err(_) => {
+                            log::debug!("TLS handshake with {} timed out", remote_addr);
+                            return;
+                        }
+                    };
+                    // Mark the requests of the clients with the verified certificate.
+                    let app = match stream.get_ref().1.peer_certificates() {
+                        Some([cert, ..]) => app.layer(Extension(ClientCertificate(cert.clone()))),
+                        _ => app,
+                    };
+                    serve_connection(&builder, stream, app, &shutdown).await
+                }
+            };
+            if let Err(err) = result {
+                log::debug!(
+                    "Failed to serve the connection from {}: {}",
+                    remote_addr,
+                    err
+                );
+            }
+        });
+    }
+
+    // Stop accepting the connections and complete the requests in flight.
+    drop(listener);
+    connections.close();
+    connections.wait().await;
+}
+
+/// Serve the requests of the connection until the client closes it or the server
+/// shuts down.
+async fn serve_connection<S>(
+    builder: &Builder<TokioExecutor>,
+    stream: S,
+    app: Router,
+    shutdown: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = TowerToHyperService::new(app);
+    let conn = builder.serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+
+    tokio::select! {
+        result = conn.as_mut() => return result,
+        _ = shutdown.cancelled() => {},
+    }
+    // Complete the request in flight and close the connection.
+    conn.as_mut().graceful_shutdown();
+    conn.await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        routing::get,
+        routing::post,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::app::health::{
+        self,
+        checks::{Readiness, ServerCheck},
+    };
+    use crate::setup::tls;
+
+    fn limits(max_concurrent_requests: usize) -> RequestLimits {
+        RequestLimits {
+            timeout: Duration::from_secs(10),
+            max_body_size: 8,
+            max_concurrent_requests,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_shedding() {
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let started_tx = Arc::new(std::sync::Mutex::new(Some(started_tx)));
+        let app = Router::new().route(
+            "/",
+            get(move || async move {
+                if let Some(started) = started_tx.lock().unwrap().take() {
+                    let _ = started.send(());
+                }
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }),
+        );
+        let app = limits(1).apply(app);
+
+        let busy = tokio::spawn(
+            app.clone()
+                .oneshot(Request::get("/").body(Body::empty()).unwrap()),
+        );
+        started_rx.await.unwrap();
+
+        let res = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], api::PROBLEM_JSON);
+
+        busy.abort();
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let app = Router::new().route("/", post(|body: String| async move { body }));
+        let app = limits(1).apply(app);
+
+        let res = app
+            .clone()
+            .oneshot(Request::post("/").body(Body::from("12345678")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = app
+            .oneshot(Request::post("/").body(Body::from("123456789")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], api::PROBLEM_JSON);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_report_is_kept() {
+        let (server, handle) = ServerCheck::watch(tokio::spawn(async {}));
+        handle.await.unwrap();
+        let readiness = Readiness::new(Duration::from_secs(1)).with_check(server);
+        let app = Router::new()
+            .route("/readyz", get(health::api::readyz))
+            .with_state(readiness);
+        let app = limits(1).apply(app);
+
+        let res = app
+            .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["status"], "not_ready");
+        assert_eq!(report["checks"][0]["name"], "server");
+    }
+
+    #[tokio::test]
+    async fn test_serve_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = ConnectionSettings {
+            keep_alive: true,
+            http2: true,
+            http1_max_buf_size: 8192,
+            http2_max_header_list_size: 8192,
+            http2_max_concurrent_streams: 10,
+            http2_keep_alive_interval: None,
+        };
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            serve(listener, Router::new(), &settings, None, shutdown),
+        )
+        .await
+        .expect("The server stops when the token is cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_serve_header_limit() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let app = Router::new().route("/livez", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = ConnectionSettings {
+            keep_alive: false,
+            http2: false,
+            http1_max_buf_size: 8192,
+            http2_max_header_list_size: 8192,
+            http2_max_concurrent_streams: 10,
+            http2_keep_alive_interval: None,
+        };
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(listener, app, &settings, None, shutdown).await }
+        });
+
+        let send = |header: String| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!("GET /livez HTTP/1.1\r\nHost: localhost\r\n{}\r\n", header);
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut res = String::new();
+            stream.read_to_string(&mut res).await.unwrap();
+            res
+        };
+        let res = send("X-Padding: small\r\n".to_owned()).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        let res = send(format!("X-Padding: {}\r\n", "a".repeat(10_000))).await;
+        assert!(res.starts_with("HTTP/1.1 431"), "{}", res);
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        let app = Router::new()
+            .route("/admin", get(|| async { "admin" }))
+            .route_layer(middleware::from_fn(tls::require_client_certificate))
+            .route("/livez", get(|| async { "ok" }));
+        let tls = Tls::load(tls::tests::test_files(true), true).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let settings = ConnectionSettings {
+            keep_alive: false,
+            http2: true,
+            http1_max_buf_size: 8192,
+            http2_max_header_list_size: 8192,
+            http2_max_concurrent_streams: 10,
+            http2_keep_alive_interval: None,
+        };
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(listener, app, &settings, Some(tls), shutdown).await }
+        });
+
+        let get = |path| format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        let res = tls::tests::send(port, false, &get("/livez")).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        let res = tls::tests::send(port, false, &get("/admin")).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 403"), "{}", res);
+        let res = tls::tests::send(port, true, &get("/admin")).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.ends_with("admin"), "{}", res);
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+}
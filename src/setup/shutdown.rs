@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::{signal, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::app::{health::checks::Readiness, Store};
+
+/// Coordinates the graceful shutdown of all the servers, so the requests in flight are
+/// completed and no new requests are routed to the stopping application.
+///
+/// The shutdown goes through the following steps:
+///
+/// 1. The readiness check starts failing
+/// 2. The drain period gives the load balancer time to notice it
+/// 3. The servers stop accepting the new connections
+/// 4. The requests in flight are completed, up to the deadline
+/// 5. The storage is closed and the logs are flushed
+pub(crate) struct Shutdown {
+    /// Cancelled when the servers should stop accepting the connections.
+    token: CancellationToken,
+    readiness: Readiness,
+    drain_period: Duration,
+    deadline: Duration,
+}
+
+impl Shutdown {
+    pub(crate) fn new(readiness: Readiness, drain_period: Duration, deadline: Duration) -> Self {
+        Shutdown {
+            token: CancellationToken::new(),
+            readiness,
+            drain_period,
+            deadline,
+        }
+    }
+
+    /// Token of the servers. The servers stop accepting the connections when it's
+    /// cancelled, and cancel it when they stop by themselves, so the other servers
+    /// stop as well.
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Wait for the shutdown signal and shut the servers down. Fails if any of the
+    /// servers stopped before the signal was received.
+    pub(crate) async fn run(self, servers: Vec<JoinHandle<()>>, store: Store) -> Result<()> {
+        let stopped_unexpectedly = tokio::select! {
+            _ = shutdown_signal() => false,
+            _ = self.token.cancelled() => true,
+        };
+
+        self.readiness.shut_down();
+        if stopped_unexpectedly {
+            log::error!("A server stopped unexpectedly, shutting down.");
+        } else {
+            log::info!(
+                "Shutting down, draining the traffic for {:?}.",
+                self.drain_period
+            );
+            tokio::time::sleep(self.drain_period).await;
+        }
+
+        log::info!("Stopping the servers.");
+        self.token.cancel();
+        let servers = futures::future::join_all(servers);
+        if tokio::time::timeout(self.deadline, servers).await.is_err() {
+            log::warn!(
+                "The requests in flight didn't complete in {:?}, dropping them.",
+                self.deadline
+            );
+        }
+
+        if let Err(err) = store.birthdays.close().await {
+            log::error!("Failed to close the storage: {:#}", err);
+        }
+        log::info!("Shutdown complete.");
+        log::logger().flush();
+
+        if stopped_unexpectedly {
+            bail!("A server stopped unexpectedly");
+        }
+        Ok(())
+    }
+}
+
+/// Wait for the signal to shut down the application, i.e. Ctrl+C or `SIGTERM`.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_server_stopped_unexpectedly() {
+        let readiness = Readiness::new(Duration::from_secs(1));
+        let shutdown = Shutdown::new(
+            readiness.clone(),
+            Duration::from_secs(60),
+            Duration::from_secs(1),
+        );
+
+        // The first server fails, the other one stops when the token is cancelled.
+        let token = shutdown.token();
+        let failed = tokio::spawn(async move { token.cancel() });
+        let token = shutdown.token();
+        let stopped = tokio::spawn(async move { token.cancelled().await });
+
+        let store = Store::new_in_mem().await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            shutdown.run(vec![failed, stopped], store),
+        )
+        .await
+        .expect("The drain period is skipped");
+
+        assert!(result.is_err());
+        assert_eq!(
+            readiness.report().await.status,
+            crate::app::health::checks::ReadinessStatus::ShuttingDown
+        );
+    }
+}
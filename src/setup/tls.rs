@@ -0,0 +1,290 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    rustls::{
+        server::{AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerifier},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::app::api::{ApiError, ApiResult};
+
+/// How often the certificate files are checked for changes, e.g. when Kubernetes
+/// updates the mounted secret.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Files of the TLS configuration of one server, in the PEM format.
+#[derive(Clone, Debug)]
+pub(crate) struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA of the client certificates. The clients presenting a certificate signed by
+    /// the CA are marked with `ClientCertificate`, see `require_client_certificate`.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// Modification times of the files, to detect the changes.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Verified certificate of the client, added to the extensions of its requests.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientCertificate(pub Certificate);
+
+impl ClientCertificate {
+    /// Hex-encoded SHA-256 digest of the certificate, identifying the client in the logs.
+    pub(crate) fn fingerprint(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.0 .0))
+    }
+}
+
+/// TLS configuration of the server, reloaded when the files change.
+/// The connections accepted before the reload keep the old configuration.
+#[derive(Clone)]
+pub(crate) struct Tls {
+    files: TlsFiles,
+    /// ALPN protocols offered to the clients.
+    alpn: Vec<Vec<u8>>,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl Tls {
+    /// Load the configuration from the files.
+    pub(crate) fn load(files: TlsFiles, http2: bool) -> Result<Self> {
+        let alpn = if http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        let config = server_config(&files, &alpn)?;
+
+        Ok(Tls {
+            files,
+            alpn,
+            config: Arc::new(RwLock::new(config)),
+        })
+    }
+
+    /// Acceptor of the new connections, with the current configuration.
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        let config = self.config.read().expect("TLS config lock poisoned");
+        TlsAcceptor::from(config.clone())
+    }
+
+    /// Load the configuration from the files again. The current configuration is kept
+    /// if the new one is invalid.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let config = server_config(&self.files, &self.alpn)?;
+        *self.config.write().expect("TLS config lock poisoned") = config;
+        log::info!("Reloaded the TLS certificate {}", self.files.cert.display());
+
+        Ok(())
+    }
+
+    /// Reload the configuration each time the files change, until the shutdown.
+    pub(crate) async fn watch(self, shutdown: CancellationToken) {
+        let mut loaded = self.files.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = shutdown.cancelled() => break,
+            }
+
+            let modified = self.files.modified();
+            if modified == loaded {
+                continue;
+            }
+            // Retry on the next tick, e.g. the certificate was written before its key.
+            match self.reload() {
+                Ok(()) => loaded = modified,
+                Err(err) => log::error!("Failed to reload the TLS configuration: {:#}", err),
+            }
+        }
+    }
+}
+
+/// Tower middleware that rejects the requests of the clients which didn't present a
+/// certificate signed by the client CA.
+pub(crate) async fn require_client_certificate(
+    request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let Some(cert) = request.extensions().get::<ClientCertificate>() else {
+        return Err(ApiError::from_status(
+            StatusCode::FORBIDDEN,
+            "A client certificate is required",
+        ));
+    };
+    log::info!(
+        "Admin request {} {} with the client certificate {}",
+        request.method(),
+        request.uri().path(),
+        cert.fingerprint()
+    );
+
+    Ok(next.run(request).await)
+}
+
+fn server_config(files: &TlsFiles, alpn: &[Vec<u8>]) -> Result<Arc<ServerConfig>> {
+    let certs = read_certs(&files.cert)?;
+    let key = read_key(&files.key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &files.client_ca {
+        Some(client_ca) => builder.with_client_cert_verifier(client_verifier(client_ca)?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("Invalid TLS certificate {}", files.cert.display()))?;
+    config.alpn_protocols = alpn.to_vec();
+
+    Ok(Arc::new(config))
+}
+
+/// The clients without a certificate are accepted as well, so the probes of
+/// Kubernetes can reach the health checks. The routes requiring the certificate
+/// are protected by `require_client_certificate`.
+fn client_verifier(client_ca: &Path) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(client_ca)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("Invalid client CA {}", client_ca.display()))?;
+    }
+
+    Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .with_context(|| format!("Reading the certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let items = rustls_pemfile::read_all(&mut open(path)?)
+        .with_context(|| format!("Reading the private key from {}", path.display()))?;
+    for item in items {
+        if let Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) = item {
+            return Ok(PrivateKey(key));
+        }
+    }
+
+    bail!("No private key found in {}", path.display())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::{
+        rustls::{ClientConfig, ServerName},
+        TlsConnector,
+    };
+
+    use super::*;
+
+    /// Path of the test certificate, key or CA, e.g. `server.pem`.
+    pub(crate) fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/setup/testdata/tls")
+            .join(name)
+    }
+
+    pub(crate) fn test_files(client_ca: bool) -> TlsFiles {
+        TlsFiles {
+            cert: testdata("server.pem"),
+            key: testdata("server.key"),
+            client_ca: client_ca.then(|| testdata("ca.pem")),
+        }
+    }
+
+    /// Send the HTTP/1.1 request over TLS and return the response.
+    pub(crate) async fn send(port: u16, client_cert: bool, request: &str) -> Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&read_certs(&testdata("ca.pem"))?[0])?;
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if client_cert {
+            builder.with_client_auth_cert(
+                read_certs(&testdata("client.pem"))?,
+                read_key(&testdata("client.key"))?,
+            )?
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        Ok(response)
+    }
+
+    #[test]
+    fn test_load_and_reload() {
+        let tls = Tls::load(test_files(true), true).unwrap();
+        tls.reload().unwrap();
+
+        let broken = TlsFiles {
+            key: testdata("client.pem"),
+            ..test_files(false)
+        };
+        let err = Tls::load(broken.clone(), true).err().unwrap();
+        assert!(err.to_string().contains("No private key"), "{}", err);
+
+        // The invalid files don't replace the loaded configuration.
+        let tls = Tls {
+            files: broken,
+            ..tls
+        };
+        assert!(tls.reload().is_err());
+    }
+}